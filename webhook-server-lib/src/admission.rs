@@ -0,0 +1,493 @@
+use base64::Engine;
+use serde::{Serialize, Deserialize};
+
+use crate::patch::{self, PatchOperation};
+use crate::resource::Resource;
+use crate::templates::Templates;
+
+pub const PATCH_TYPE_JSON_PATCH: &str = "JSONPatch";
+
+/// The AdmissionReview versions served. The response is always sent in the version of the request.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AdmissionVersion {
+    /// Sent by clusters older than 1.16, or by webhooks configured with `admissionReviewVersions: [v1beta1]`
+    V1beta1,
+    V1,
+}
+
+impl AdmissionVersion {
+    pub fn from_api_version(api_version: &str) -> Result<AdmissionVersion, String> {
+        match api_version {
+            "admission.k8s.io/v1" => Ok(AdmissionVersion::V1),
+            "admission.k8s.io/v1beta1" => Ok(AdmissionVersion::V1beta1),
+            other => Err(format!("Unsupported AdmissionReview apiVersion {}", other)),
+        }
+    }
+
+    pub fn api_version(&self) -> &'static str {
+        match self {
+            AdmissionVersion::V1 => "admission.k8s.io/v1",
+            AdmissionVersion::V1beta1 => "admission.k8s.io/v1beta1",
+        }
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionReview {
+    pub api_version: String,
+    pub kind: String,
+    pub request: Option<AdmissionRequest>,
+    pub response: Option<AdmissionResponse>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionRequest {
+    pub uid: String,
+    pub name: Option<String>,
+    pub namespace: Option<String>,
+    pub operation: Option<Operation>,
+    pub user_info: Option<UserInfo>,
+    pub dry_run: Option<bool>,
+    pub object: Option<serde_json::Value>,
+    pub old_object: Option<serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Operation {
+    Create,
+    Update,
+    Delete,
+    Connect,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UserInfo {
+    pub username: Option<String>,
+    pub uid: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub extra: Option<serde_json::Value>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionResponse {
+    pub uid: String,
+    pub allowed: bool,
+    pub patch_type: Option<String>,
+    pub patch: Option<String>,
+    /// Shown to the user making the request, e.g. by kubectl
+    pub warnings: Option<Vec<String>>,
+    /// Why the request isn't allowed
+    pub status: Option<Status>,
+}
+
+/// The parts of a Kubernetes `Status` used to explain a denied request
+#[serde_with::skip_serializing_none]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    pub status: Option<String>,
+    pub code: Option<u16>,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+}
+
+impl AdmissionResponse {
+
+    fn allowed(uid: &str) -> AdmissionResponse {
+        AdmissionResponse {
+            uid: uid.to_string(),
+            allowed: true,
+            patch_type: None,
+            patch: None,
+            warnings: None,
+            status: None,
+        }
+    }
+
+    fn denied(uid: &str, message: String) -> AdmissionResponse {
+        AdmissionResponse {
+            allowed: false,
+            status: Some(Status {
+                status: Some(String::from("Failure")),
+                code: Some(403),
+                reason: Some(String::from("Forbidden")),
+                message: Some(message),
+            }),
+            ..AdmissionResponse::allowed(uid)
+        }
+    }
+
+    fn with_warnings(mut self, warnings: Vec<String>) -> AdmissionResponse {
+        if !warnings.is_empty() {
+            self.warnings = Some(warnings);
+        }
+        self
+    }
+
+    fn with_patch(mut self, operations: &[PatchOperation]) -> serde_json::Result<AdmissionResponse> {
+        if !operations.is_empty() {
+            let json = serde_json::to_string(operations)?;
+            self.patch_type = Some(PATCH_TYPE_JSON_PATCH.to_string());
+            self.patch = Some(base64::engine::general_purpose::STANDARD.encode(json));
+        }
+        Ok(self)
+    }
+
+}
+
+impl AdmissionReview {
+
+    pub fn from_json(rep: &[u8]) -> serde_json::Result<AdmissionReview> {
+        serde_json::from_slice(rep)
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    pub fn version(&self) -> Result<AdmissionVersion, String> {
+        AdmissionVersion::from_api_version(&self.api_version)
+    }
+
+    /// v1 requires the response's apiVersion and kind to match the request's, and v1beta1 is
+    /// happy with them, so they're always set. The patch type, which v1 requires alongside a patch, is set with it by `with_patch`.
+    fn respond(version: AdmissionVersion, response: AdmissionResponse) -> AdmissionReview {
+        AdmissionReview {
+            api_version: version.api_version().to_string(),
+            kind: String::from("AdmissionReview"),
+            request: None,
+            response: Some(response),
+        }
+    }
+
+    /// Applies the matching templates to the object in the request, returning the review to send back.
+    /// Objects that can't be mutated are always allowed through unchanged.
+    pub fn mutate(&self, templates: &Templates) -> Result<AdmissionReview, String> {
+        let (version, request, original) = match self.admitted_object()? {
+            (version, request, Some(original)) => (version, request, original),
+            (version, request, None) => return Ok(Self::respond(version, AdmissionResponse::allowed(&request.uid))),
+        };
+        let response = AdmissionResponse::allowed(&request.uid);
+        let applied = templates.apply(&original, Some(request));
+        //Metadata created to hold the namespace isn't in the object sent, so the patch has to add it
+        let mut sent = original.clone();
+        if request.object.as_ref().map(|object| object.get("metadata").is_none()).unwrap_or(false) {
+            sent.metadata = None;
+        }
+        let operations = applied.as_ref()
+            .map(|applied| patch::diff_resources(&sent, &applied.resource))
+            .transpose()
+            .map_err(|err| err.to_string())?
+            .unwrap_or_default();
+        let (names, errors) = applied.map(|applied| (applied.templates, applied.errors)).unwrap_or_default();
+        log::info!("Request {}: {} patch operation(s) for {} from templates {:?}", request.uid, operations.len(), original, names);
+        response.with_warnings(errors)
+            .with_patch(&operations)
+            .map(|response| Self::respond(version, response))
+            .map_err(|err| err.to_string())
+    }
+
+    /// Checks the object in the request against the policies, denying it if it breaks any.
+    /// Objects that can't be read are allowed, as they are by `mutate`.
+    pub fn validate(&self, templates: &Templates) -> Result<AdmissionReview, String> {
+        let (version, request, object) = match self.admitted_object()? {
+            (version, request, Some(object)) => (version, request, object),
+            (version, request, None) => return Ok(Self::respond(version, AdmissionResponse::allowed(&request.uid))),
+        };
+        let violations = templates.validate(&object);
+        log::info!("Request {}: {} policy violation(s) for {}", request.uid, violations.len(), object);
+        if violations.is_empty() {
+            Ok(Self::respond(version, AdmissionResponse::allowed(&request.uid)))
+        } else {
+            Ok(Self::respond(version, AdmissionResponse::denied(&request.uid, violations.join("; "))))
+        }
+    }
+
+    /// The version, the request and the object in it, if there is one that can be read
+    fn admitted_object(&self) -> Result<(AdmissionVersion, &AdmissionRequest, Option<Resource<serde_json::Value>>), String> {
+        let version = self.version()?;
+        let request = self.request.as_ref().ok_or("AdmissionReview has no request")?;
+        let object = request.object.as_ref().and_then(|object| {
+            match serde_json::from_value::<Resource<serde_json::Value>>(object.clone()) {
+                Ok(resource) => Some(with_namespace(resource, &request.namespace)),
+                Err(err) => {
+                    log::warn!("Unable to read object in request {} - allowing unchanged: {}", request.uid, err);
+                    None
+                }
+            }
+        });
+        Ok((version, request, object))
+    }
+
+}
+
+/// The object's namespace isn't always populated on create, so fall back to the one on the request,
+/// creating the metadata to hold it if the object has none
+fn with_namespace(mut resource: Resource<serde_json::Value>, namespace: &Option<String>) -> Resource<serde_json::Value> {
+    if namespace.is_some() {
+        let metadata = resource.metadata.get_or_insert_with(Default::default);
+        if metadata.namespace.is_none() {
+            metadata.namespace = namespace.clone();
+        }
+    }
+    resource
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+
+    use crate::templates::Templates;
+
+    use super::AdmissionReview;
+
+    fn templates() -> Templates {
+        Templates::construct_templates(r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          metadata:
+            labels:
+              app: web
+          spec:
+            containers:
+              env:
+              - name: BOB
+                value: A_JOB
+        "#).unwrap()
+    }
+
+    fn review(object: serde_json::Value) -> AdmissionReview {
+        review_in("admission.k8s.io/v1", object)
+    }
+
+    fn review_in(api_version: &str, object: serde_json::Value) -> AdmissionReview {
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": api_version,
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "namespace": "default",
+                "object": object,
+            }
+        })).unwrap()
+    }
+
+    fn decode_patch(review: &AdmissionReview) -> serde_json::Value {
+        let patch = review.response.as_ref().unwrap().patch.as_ref().unwrap();
+        let bytes = base64::engine::general_purpose::STANDARD.decode(patch).unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn mutate_returns_patch_for_matching_object() {
+        let request = review(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "web-1", "labels": { "app": "web" } },
+            "spec": { "containers": [ { "name": "web", "image": "nginx" } ] }
+        }));
+        let response = request.mutate(&templates()).unwrap();
+        assert_eq!("admission.k8s.io/v1", response.api_version);
+        assert_eq!("AdmissionReview", response.kind);
+        assert!(response.request.is_none());
+        let admission_response = response.response.as_ref().unwrap();
+        assert_eq!("705ab4f5-6393-11e8-b7cc-42010a800002", admission_response.uid);
+        assert!(admission_response.allowed);
+        assert_eq!(Some(String::from("JSONPatch")), admission_response.patch_type);
+        let expected = serde_json::json!([
+            { "op": "add", "path": "/spec/containers/0/env", "value": [ { "name": "BOB", "value": "A_JOB" } ] },
+        ]);
+        assert_eq!(expected, decode_patch(&response));
+    }
+
+    #[test]
+    fn mutate_allows_non_matching_object_without_patch() {
+        let request = review(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Service",
+            "metadata": { "name": "web" }
+        }));
+        let response = request.mutate(&templates()).unwrap().response.unwrap();
+        assert!(response.allowed);
+        assert_eq!(None, response.patch_type);
+        assert_eq!(None, response.patch);
+    }
+
+    #[test]
+    fn mutate_allows_unreadable_object() {
+        let request = review(serde_json::json!({ "kind": "Pod" }));
+        let response = request.mutate(&templates()).unwrap().response.unwrap();
+        assert!(response.allowed);
+        assert_eq!(None, response.patch);
+    }
+
+    #[test]
+    fn mutate_uses_request_namespace_for_matching() {
+        let templates = Templates::construct_templates(r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          metadata:
+            namespace: default
+          spec:
+            injected: true
+        "#).unwrap();
+        let request = review(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "generateName": "web-" }
+        }));
+        let response = request.mutate(&templates).unwrap();
+        let expected = serde_json::json!([
+            { "op": "add", "path": "/spec", "value": { "injected": true } },
+        ]);
+        assert_eq!(expected, decode_patch(&response));
+    }
+
+    #[test]
+    fn mutate_uses_request_namespace_for_object_without_metadata() {
+        let templates = Templates::construct_templates(r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          namespaces: [ default ]
+          spec:
+            injected: true
+        "#).unwrap();
+        let request = review(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod"
+        }));
+        let response = request.mutate(&templates).unwrap();
+        let expected = serde_json::json!([
+            { "op": "add", "path": "/metadata", "value": { "namespace": "default" } },
+            { "op": "add", "path": "/spec", "value": { "injected": true } },
+        ]);
+        assert_eq!(expected, decode_patch(&response));
+    }
+
+    #[test]
+    fn mutate_matches_on_request_details() {
+        let templates = Templates::construct_templates(r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          operations: [ CREATE ]
+          users: [ "!system:serviceaccount:kube-system:*" ]
+          spec:
+            injected: true
+        "#).unwrap();
+        let review_by = |operation: &str, username: &str| -> AdmissionReview {
+            serde_json::from_value(serde_json::json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                    "operation": operation,
+                    "userInfo": { "username": username, "groups": [ "system:authenticated" ] },
+                    "dryRun": false,
+                    "object": { "apiVersion": "v1", "kind": "Pod" },
+                }
+            })).unwrap()
+        };
+        let patched = |review: AdmissionReview| review.mutate(&templates).unwrap().response.unwrap().patch.is_some();
+        assert!(patched(review_by("CREATE", "alice")));
+        assert!(!patched(review_by("UPDATE", "alice")));
+        assert!(!patched(review_by("CREATE", "system:serviceaccount:kube-system:replicaset-controller")));
+    }
+
+    #[test]
+    fn mutate_warns_of_templates_not_applied() {
+        let templates = Templates::construct_templates(r#"
+        templates:
+        - name: broken
+          apiVersion: v1
+          kind: Pod
+          body: "spec: {{ object.metadata.name + 1 }}"
+        "#).unwrap();
+        let request = review(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "web-1" }
+        }));
+        let response = request.mutate(&templates).unwrap().response.unwrap();
+        assert!(response.allowed);
+        assert_eq!(None, response.patch);
+        let warnings = response.warnings.unwrap();
+        assert!(warnings[0].contains("broken"), "{:?}", warnings);
+    }
+
+    #[test]
+    fn validate_denies_policy_violations() {
+        let templates = Templates::construct_templates(r#"
+        policies:
+        - name: team
+          requiredLabels: [ team ]
+        - name: registries
+          forbiddenRegistries: [ docker.io ]
+        "#).unwrap();
+        let request = review(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "web-1" },
+            "spec": { "containers": [ { "name": "web", "image": "nginx" } ] }
+        }));
+        let response = request.validate(&templates).unwrap().response.unwrap();
+        assert!(!response.allowed);
+        assert_eq!(None, response.patch);
+        let status = response.status.unwrap();
+        assert_eq!(Some(403), status.code);
+        assert_eq!(Some(String::from("team: missing required label team; registries: container web uses image nginx from forbidden registry docker.io")), status.message);
+    }
+
+    #[test]
+    fn validate_allows_compliant_object() {
+        let templates = Templates::construct_templates("policies: [ { name: team, requiredLabels: [ team ] } ]").unwrap();
+        let request = review(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "web-1", "labels": { "team": "a" } }
+        }));
+        let response = request.validate(&templates).unwrap().response.unwrap();
+        assert!(response.allowed);
+        assert_eq!(None, response.status);
+    }
+
+    #[test]
+    fn responds_in_version_of_request() {
+        let object = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "web-1", "labels": { "app": "web" } },
+            "spec": { "containers": [ { "name": "web", "image": "nginx" } ] }
+        });
+        let response = review_in("admission.k8s.io/v1beta1", object.clone()).mutate(&templates()).unwrap();
+        assert_eq!("admission.k8s.io/v1beta1", response.api_version);
+        assert_eq!(Some(String::from("JSONPatch")), response.response.as_ref().unwrap().patch_type);
+        let response = review_in("admission.k8s.io/v1beta1", object.clone()).validate(&templates()).unwrap();
+        assert_eq!("admission.k8s.io/v1beta1", response.api_version);
+        let err = review_in("admission.k8s.io/v2", object).mutate(&templates()).unwrap_err();
+        assert!(err.contains("admission.k8s.io/v2"), "{}", err);
+    }
+
+    #[test]
+    fn mutate_fails_without_request() {
+        let review: AdmissionReview = serde_json::from_value(serde_json::json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+        })).unwrap();
+        assert!(review.mutate(&templates()).is_err());
+    }
+
+}
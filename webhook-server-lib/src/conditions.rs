@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use cel_interpreter::{Context, Program};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// A CEL expression as written in a template's `matchConditions:`, as in Kubernetes admission webhooks
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MatchConditionConfig {
+    name: String,
+    expression: String,
+}
+
+/// The variables a match condition is evaluated with, `Value::Null` when not known
+pub struct Variables<'a> {
+    pub object: &'a Value,
+    pub old_object: &'a Value,
+    pub request: &'a Value,
+    pub namespace_object: &'a Value,
+}
+
+/// A compiled match condition, which has to evaluate to `true` for the template to be applied
+#[derive(Clone)]
+pub struct MatchCondition {
+    name: String,
+    program: Arc<Program>,
+}
+
+impl MatchCondition {
+    pub fn compile(config: MatchConditionConfig) -> Result<MatchCondition, String> {
+        let program = Program::compile(&config.expression)
+            .map_err(|err| format!("Invalid match condition {} '{}': {}", config.name, config.expression, err))?;
+        Ok(MatchCondition { name: config.name, program: Arc::new(program) })
+    }
+
+    /// Conditions that fail to evaluate, or don't give a boolean, don't match
+    pub fn matches(&self, variables: &Variables) -> Result<bool, String> {
+        let mut context = Context::default();
+        for (name, value) in [
+            ("object", variables.object),
+            ("oldObject", variables.old_object),
+            ("request", variables.request),
+            ("namespaceObject", variables.namespace_object),
+        ] {
+            context.add_variable(name, value).map_err(|err| format!("Unable to pass {} to match condition {}: {}", name, self.name, err))?;
+        }
+        match self.program.execute(&context) {
+            Ok(cel_interpreter::Value::Bool(matches)) => Ok(matches),
+            Ok(other) => Err(format!("Match condition {} gave {:?} rather than a boolean", self.name, other)),
+            Err(err) => Err(format!("Match condition {} failed: {}", self.name, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{MatchCondition, Variables};
+
+    fn condition(expression: &str) -> MatchCondition {
+        MatchCondition::compile(serde_yaml::from_str(&format!("{{ name: test, expression: '{}' }}", expression)).unwrap()).unwrap()
+    }
+
+    fn evaluate(expression: &str) -> Result<bool, String> {
+        let object = json!({ "kind": "Pod", "metadata": { "labels": { "app": "web" } }, "spec": { "replicas": 3 } });
+        let old_object = json!({ "kind": "Pod", "spec": { "replicas": 2 } });
+        let request = json!({ "operation": "UPDATE", "userInfo": { "username": "alice" } });
+        let namespace_object = json!({ "metadata": { "name": "team-a", "labels": { "env": "prod" } } });
+        condition(expression).matches(&Variables {
+            object: &object,
+            old_object: &old_object,
+            request: &request,
+            namespace_object: &namespace_object,
+        })
+    }
+
+    #[test]
+    fn evaluates_against_variables() {
+        assert_eq!(Ok(true), evaluate("object.metadata.labels.app == \"web\""));
+        assert_eq!(Ok(true), evaluate("object.spec.replicas > oldObject.spec.replicas"));
+        assert_eq!(Ok(true), evaluate("request.operation in [\"CREATE\", \"UPDATE\"]"));
+        assert_eq!(Ok(false), evaluate("request.userInfo.username.startsWith(\"system:\")"));
+        assert_eq!(Ok(true), evaluate("namespaceObject.metadata.labels.env == \"prod\""));
+        assert_eq!(Ok(false), evaluate("has(object.spec.hostNetwork)"));
+    }
+
+    #[test]
+    fn non_boolean_and_failing_conditions_are_errors() {
+        assert!(evaluate("object.kind").unwrap_err().contains("boolean"));
+        assert!(evaluate("object.spec.missing == 1").is_err());
+    }
+
+    #[test]
+    fn null_variables() {
+        let object = json!({ "kind": "Pod" });
+        let result = condition("oldObject == null").matches(&Variables {
+            object: &object,
+            old_object: &Value::Null,
+            request: &Value::Null,
+            namespace_object: &Value::Null,
+        });
+        assert_eq!(Ok(true), result);
+    }
+
+    #[test]
+    fn rejects_invalid_expression() {
+        let err = MatchCondition::compile(serde_yaml::from_str("{ name: broken, expression: 'object.' }").unwrap()).err().unwrap();
+        assert!(err.contains("broken"), "{}", err);
+    }
+
+}
@@ -8,6 +8,77 @@ pub struct Args {
     pub address: String,
     #[arg(short, long, default_value_t = 3000)]
     pub port: u16,
+    /// A templates file, a directory of them or a glob such as `templates/*.yaml`
     #[arg(short, long, default_value_t = String::from("templates.yaml"))]
     pub templates_file: String,
+    /// PEM certificate chain to serve HTTPS with, plain HTTP is served if not set
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<String>,
+    /// PEM private key for the certificate
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<String>,
+    /// How often to check the templates, certificate and key files for changes, and to retry the Kubernetes API
+    #[arg(long, default_value_t = 10_000)]
+    pub reload_interval_ms: u64,
+    /// Also serve the templates of MutationTemplate resources, watched through the Kubernetes API
+    #[arg(long)]
+    pub template_resources: bool,
+    /// The Kubernetes API to watch MutationTemplates through, rather than the cluster the webhook runs in,
+    /// e.g. `http://127.0.0.1:8001` with `kubectl proxy`
+    #[arg(long, requires = "template_resources")]
+    pub kube_api_url: Option<String>,
+    /// Mutates requests POSTed to PATH with the templates in FILE, as well as those to /mutate
+    /// with the templates file, e.g. `--route /mutate/sidecars=sidecars.yaml`. Requests POSTed to
+    /// PATH/validate are checked against the policies in FILE. Can be repeated.
+    #[arg(long = "route", value_name = "PATH=FILE", value_parser = parse_route)]
+    pub routes: Vec<Route>,
+}
+
+/// A path served with its own templates file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub path: String,
+    pub templates_file: String,
+}
+
+/// Paths served whatever the routes are
+const RESERVED_PATHS: [&str; 3] = ["/", "/mutate", "/validate"];
+
+fn parse_route(route: &str) -> Result<Route, String> {
+    let (path, templates_file) = route.split_once('=').ok_or_else(|| format!("{} isn't PATH=FILE", route))?;
+    if !path.starts_with('/') {
+        return Err(format!("Path {} doesn't start with /", path));
+    }
+    if RESERVED_PATHS.contains(&path) {
+        return Err(format!("Path {} is already served", path));
+    }
+    if templates_file.is_empty() {
+        return Err(format!("Path {} has no templates file", path));
+    }
+    Ok(Route { path: path.to_string(), templates_file: templates_file.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{Args, Route};
+
+    #[test]
+    fn parses_routes() {
+        let args = Args::try_parse_from(["webhook", "--route", "/mutate/sidecars=sidecars.yaml", "--route", "/mutate/defaults=/etc/defaults.yaml"]).unwrap();
+        assert_eq!(vec![
+            Route { path: String::from("/mutate/sidecars"), templates_file: String::from("sidecars.yaml") },
+            Route { path: String::from("/mutate/defaults"), templates_file: String::from("/etc/defaults.yaml") },
+        ], args.routes);
+        assert!(Args::try_parse_from(["webhook"]).unwrap().routes.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        for route in ["sidecars.yaml", "mutate/sidecars=sidecars.yaml", "/mutate=sidecars.yaml", "/mutate/sidecars="] {
+            assert!(Args::try_parse_from(["webhook", "--route", route]).is_err(), "{}", route);
+        }
+    }
+
 }
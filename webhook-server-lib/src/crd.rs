@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+use crate::loader::Document;
+use crate::templates::Templates;
+
+pub const GROUP: &str = "webhook-server.io";
+pub const VERSION: &str = "v1alpha1";
+pub const KIND: &str = "MutationTemplate";
+const PLURAL: &str = "mutationtemplates";
+
+/// Where Kubernetes mounts the pod's service account token and the API server's CA
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// A cluster scoped MutationTemplate. Its spec is written as an entry of the templates file's
+/// `templates:`, named after the resource unless it has a `name`.
+#[derive(Deserialize, Debug)]
+struct MutationTemplate {
+    metadata: Metadata,
+    #[serde(default)]
+    spec: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Metadata {
+    //Left out of bookmark events
+    #[serde(default)]
+    name: String,
+    resource_version: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MutationTemplateList {
+    metadata: ListMetadata,
+    items: Vec<MutationTemplate>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ListMetadata {
+    resource_version: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    object: serde_json::Value,
+}
+
+impl MutationTemplate {
+    /// The template as a document like those of a templates file
+    fn document(&self) -> Result<Document, String> {
+        let mut spec = match &self.spec {
+            serde_json::Value::Object(spec) => spec.clone(),
+            _ => return Err(String::from("spec isn't an object")),
+        };
+        spec.entry("name").or_insert_with(|| serde_json::Value::from(self.metadata.name.as_str()));
+        let value = serde_yaml::to_value(serde_json::json!({ "templates": [ spec ] }))
+            .map_err(|err| err.to_string())?;
+        Ok(Document { source: Some(format!("{} {}", KIND, self.metadata.name)), value, trusted: false })
+    }
+}
+
+/// The templates of the MutationTemplates seen so far
+#[derive(Clone, Default)]
+pub struct ResourceTemplates {
+    /// By resource name, the last version of each resource's template that loaded, with its template name
+    documents: Arc<Mutex<BTreeMap<String, (String, Document)>>>,
+}
+
+impl ResourceTemplates {
+    /// The templates to load alongside the templates file's, whose template names are `file_names`.
+    /// A resource whose template has one of those names, or the name of an earlier resource's
+    /// template, is left out for as long as the clash lasts.
+    pub(crate) fn documents(&self, file_names: &BTreeSet<String>) -> Vec<Document> {
+        let documents = self.documents.lock().unwrap();
+        let mut names = BTreeMap::new();
+        let mut loaded = Vec::new();
+        for (resource, (name, document)) in documents.iter() {
+            if file_names.contains(name) {
+                log::error!("Ignoring {} {}: template name '{}' is used in the templates file", KIND, resource, name);
+            } else if let Some(other) = names.get(name) {
+                log::error!("Ignoring {} {}: template name '{}' is used by {} {}", KIND, resource, name, KIND, other);
+            } else {
+                names.insert(name, resource);
+                loaded.push(document.clone());
+            }
+        }
+        loaded
+    }
+
+    /// Adds, or replaces, the template. One that doesn't load on its own, including one using
+    /// `$valueFrom`, is left out so it can't stop the other templates from loading, with the
+    /// resource's last version that did load kept instead.
+    fn insert(&self, template: &MutationTemplate) {
+        let loaded = template.document().and_then(|document| {
+            let name = Templates::construct_documents(vec![document.clone()])?.names().into_iter().next().unwrap_or_default();
+            Ok((name, document))
+        });
+        let mut documents = self.documents.lock().unwrap();
+        match loaded {
+            Ok(loaded) => {
+                documents.insert(template.metadata.name.clone(), loaded);
+            },
+            Err(err) if documents.contains_key(&template.metadata.name) =>
+                log::error!("Ignoring update to {} {}, keeping its previous version: {}", KIND, template.metadata.name, err),
+            Err(err) => log::error!("Ignoring {} {}: {}", KIND, template.metadata.name, err),
+        }
+    }
+
+    fn remove(&self, name: &str) {
+        self.documents.lock().unwrap().remove(name);
+    }
+
+    fn replace(&self, templates: &[MutationTemplate]) {
+        self.documents.lock().unwrap().clear();
+        for template in templates {
+            self.insert(template);
+        }
+    }
+}
+
+/// Reads MutationTemplates from the Kubernetes API
+#[derive(Clone)]
+pub struct ApiClient {
+    url: String,
+    /// Read for every request, as service account tokens are rotated
+    token_file: Option<String>,
+    client: reqwest::Client,
+}
+
+impl ApiClient {
+    /// Uses the API server the pod runs in, with its service account
+    pub fn in_cluster() -> Result<ApiClient, String> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| "KUBERNETES_SERVICE_HOST isn't set - not running in a cluster?")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| String::from("443"));
+        let ca_file = format!("{}/ca.crt", SERVICE_ACCOUNT_DIR);
+        let ca = std::fs::read(&ca_file)
+            .map_err(|err| format!("Unable to read {}: {}", ca_file, err))
+            .and_then(|pem| reqwest::Certificate::from_pem(&pem).map_err(|err| format!("Invalid CA in {}: {}", ca_file, err)))?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(ca)
+            .build()
+            .map_err(|err| err.to_string())?;
+        let host = if host.contains(':') { format!("[{}]", host) } else { host };
+        Ok(ApiClient {
+            url: format!("https://{}:{}", host, port),
+            token_file: Some(format!("{}/token", SERVICE_ACCOUNT_DIR)),
+            client,
+        })
+    }
+
+    /// Uses the API server at `url` without credentials, e.g. through `kubectl proxy`
+    pub fn new(url: &str) -> ApiClient {
+        ApiClient {
+            url: url.trim_end_matches('/').to_string(),
+            token_file: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn get(&self, query: &str) -> Result<reqwest::Response, String> {
+        let mut request = self.client.get(format!("{}/apis/{}/{}/{}{}", self.url, GROUP, VERSION, PLURAL, query));
+        if let Some(token_file) = self.token_file.as_ref() {
+            let token = std::fs::read_to_string(token_file).map_err(|err| format!("Unable to read {}: {}", token_file, err))?;
+            request = request.bearer_auth(token.trim());
+        }
+        let response = request.send().await.map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(format!("{} from the API server: {}", status, response.text().await.unwrap_or_default()));
+        }
+        Ok(response)
+    }
+
+    async fn list(&self) -> Result<MutationTemplateList, String> {
+        self.get("").await?.json().await.map_err(|err| format!("Unable to read {} list: {}", KIND, err))
+    }
+
+    /// Applies the events from `resource_version` on to the templates until the API server ends the
+    /// watch. Returns whether the watch can be resumed, which it can't if the version is too old.
+    async fn watch<F>(&self, templates: &ResourceTemplates, resource_version: &mut String, on_change: &Arc<F>) -> Result<bool, String>
+    where F: Fn() + Send + Sync + 'static {
+        let query = format!("?watch=true&allowWatchBookmarks=true&timeoutSeconds=300&resourceVersion={}", resource_version);
+        let mut response = self.get(&query).await?;
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|err| err.to_string())? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let event: WatchEvent = serde_json::from_slice(&line).map_err(|err| format!("Invalid watch event: {}", err))?;
+                if event.event_type == "ERROR" {
+                    //The object is a Status, 410 Gone meaning the version is too old to watch from
+                    if event.object.get("code").and_then(serde_json::Value::as_u64) == Some(410) {
+                        return Ok(false);
+                    }
+                    return Err(format!("Watch failed: {}", event.object));
+                }
+                let template: MutationTemplate = serde_json::from_value(event.object)
+                    .map_err(|err| format!("Invalid {} in {} event: {}", KIND, event.event_type, err))?;
+                if let Some(version) = template.metadata.resource_version.as_ref() {
+                    resource_version.clone_from(version);
+                }
+                match event.event_type.as_str() {
+                    "ADDED" | "MODIFIED" => {
+                        log::info!("{} {} {}", KIND, template.metadata.name, event.event_type.to_lowercase());
+                        templates.insert(&template);
+                        changed(on_change).await;
+                    },
+                    "DELETED" => {
+                        log::info!("{} {} deleted", KIND, template.metadata.name);
+                        templates.remove(&template.metadata.name);
+                        changed(on_change).await;
+                    },
+                    _ => {},
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Calls `on_change`, which reads files, on a blocking thread, waiting for it to finish
+async fn changed<F>(on_change: &Arc<F>)
+where F: Fn() + Send + Sync + 'static {
+    let on_change = on_change.clone();
+    if let Err(err) = tokio::task::spawn_blocking(move || on_change()).await {
+        log::error!("Failed to reload templates after {}s changed: {}", KIND, err);
+    }
+}
+
+/// Lists and then watches the MutationTemplates, keeping `templates` up to date and calling `on_change`
+/// whenever they change. After a failure the templates are listed again once `retry` has passed.
+/// A watch is only started again once `retry` has passed since the last one started, so a server
+/// ending watches straight away isn't flooded with requests.
+pub fn watch_templates<F>(client: ApiClient, templates: ResourceTemplates, retry: Duration, on_change: F) -> JoinHandle<()>
+where F: Fn() + Send + Sync + 'static {
+    let on_change = Arc::new(on_change);
+    tokio::spawn(async move {
+        loop {
+            let mut resource_version = match client.list().await {
+                Ok(list) => {
+                    log::info!("Listed {} {}(s)", list.items.len(), KIND);
+                    templates.replace(&list.items);
+                    changed(&on_change).await;
+                    list.metadata.resource_version.unwrap_or_default()
+                },
+                Err(err) => {
+                    log::error!("Failed to list {}s: {}", KIND, err);
+                    tokio::time::sleep(retry).await;
+                    continue;
+                },
+            };
+            loop {
+                let started = tokio::time::Instant::now();
+                match client.watch(&templates, &mut resource_version, &on_change).await {
+                    Ok(true) => tokio::time::sleep_until(started + retry).await,
+                    Ok(false) => {
+                        tokio::time::sleep_until(started + retry).await;
+                        break;
+                    },
+                    Err(err) => {
+                        log::error!("Failed watching {}s: {}", KIND, err);
+                        tokio::time::sleep(retry).await;
+                        break;
+                    },
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MutationTemplate, ResourceTemplates};
+
+    fn mutation_template(json: serde_json::Value) -> MutationTemplate {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn converts_to_documents_named_after_the_resource() {
+        let template = mutation_template(serde_json::json!({
+            "apiVersion": "webhook-server.io/v1alpha1",
+            "kind": "MutationTemplate",
+            "metadata": { "name": "sidecar", "resourceVersion": "12" },
+            "spec": { "apiVersion": "v1", "kind": "Pod", "priority": 2, "spec": { "restartPolicy": "Never" } }
+        }));
+        let document = template.document().unwrap();
+        assert_eq!(Some("MutationTemplate sidecar"), document.source.as_deref());
+        let expected: serde_yaml::Value = serde_yaml::from_str(r#"
+        templates:
+        - name: sidecar
+          apiVersion: v1
+          kind: Pod
+          priority: 2
+          spec:
+            restartPolicy: Never
+        "#).unwrap();
+        assert_eq!(expected, document.value);
+    }
+
+    fn sources(templates: &ResourceTemplates, file_names: &[&str]) -> Vec<String> {
+        let file_names = file_names.iter().map(|name| name.to_string()).collect();
+        templates.documents(&file_names).into_iter().map(|document| document.source.unwrap_or_default()).collect()
+    }
+
+    #[test]
+    fn leaves_out_invalid_templates() {
+        let templates = ResourceTemplates::default();
+        templates.insert(&mutation_template(serde_json::json!({
+            "metadata": { "name": "a" },
+            "spec": { "apiVersion": "v1", "kind": "Pod", "spec": { "restartPolicy": "Never" } }
+        })));
+        templates.insert(&mutation_template(serde_json::json!({
+            "metadata": { "name": "a" },
+            "spec": { "apiVersion": "v1", "kind": "Pod", "when": [ { "path": "$[" } ] }
+        })));
+        let documents = templates.documents(&Default::default());
+        assert_eq!(1, documents.len(), "invalid update should keep the previous version");
+        assert_eq!(Some(&serde_yaml::Value::from("Never")), documents[0].value["templates"][0]["spec"].get("restartPolicy"));
+        templates.insert(&mutation_template(serde_json::json!({ "metadata": { "name": "b" }, "spec": "not a template" })));
+        assert_eq!(vec!["MutationTemplate a"], sources(&templates, &[]));
+        templates.remove("a");
+        assert!(sources(&templates, &[]).is_empty());
+    }
+
+    #[test]
+    fn leaves_out_templates_with_names_in_use_while_they_are() {
+        let templates = ResourceTemplates::default();
+        templates.insert(&mutation_template(serde_json::json!({
+            "metadata": { "name": "a" },
+            "spec": { "name": "baseline", "apiVersion": "v1", "kind": "Pod" }
+        })));
+        templates.insert(&mutation_template(serde_json::json!({
+            "metadata": { "name": "b" },
+            "spec": { "name": "sidecar", "apiVersion": "v1", "kind": "Pod" }
+        })));
+        templates.insert(&mutation_template(serde_json::json!({
+            "metadata": { "name": "c" },
+            "spec": { "name": "sidecar", "apiVersion": "v1", "kind": "Pod" }
+        })));
+        assert_eq!(vec!["MutationTemplate b"], sources(&templates, &["baseline"]), "names used by the file or an earlier resource");
+        assert!(sources(&templates, &["baseline", "sidecar"]).is_empty(), "name now used in the templates file");
+        assert_eq!(vec!["MutationTemplate a", "MutationTemplate b"], sources(&templates, &[]), "clashes gone");
+        templates.remove("b");
+        assert_eq!(vec!["MutationTemplate a", "MutationTemplate c"], sources(&templates, &[]), "c no longer clashes with b");
+    }
+
+    #[test]
+    fn leaves_out_templates_reading_the_webhooks_values() {
+        let templates = ResourceTemplates::default();
+        templates.insert(&mutation_template(serde_json::json!({
+            "metadata": { "name": "token" },
+            "spec": {
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "spec": { "containers": [ { "name": "app", "env": [
+                    { "name": "TOKEN", "value": { "$valueFrom": { "file": "/var/run/secrets/kubernetes.io/serviceaccount/token" } } }
+                ] } ] }
+            }
+        })));
+        assert!(sources(&templates, &[]).is_empty());
+    }
+
+}
@@ -0,0 +1,208 @@
+use std::cmp::Ordering;
+
+use serde::Deserialize;
+
+use crate::selector::glob_matches;
+
+/// Kubernetes API version stability, in increasing order
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+enum Stability {
+    Alpha(u32),
+    Beta(u32),
+    Ga,
+}
+
+/// A version such as `v1`, `v2beta1` or `v1alpha3`. Ordered by major version, then
+/// alpha before beta before GA, e.g. `v1alpha1 < v1beta1 < v1beta2 < v1 < v2alpha1`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+struct KubeVersion {
+    major: u32,
+    stability: Stability,
+}
+
+impl KubeVersion {
+    fn parse(version: &str) -> Option<KubeVersion> {
+        let rest = version.strip_prefix('v')?;
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let major = rest[..digits].parse().ok()?;
+        let stability = match &rest[digits..] {
+            "" => Stability::Ga,
+            suffix => match (suffix.strip_prefix("alpha"), suffix.strip_prefix("beta")) {
+                (Some(minor), _) => Stability::Alpha(minor.parse().ok()?),
+                (_, Some(minor)) => Stability::Beta(minor.parse().ok()?),
+                _ => return None,
+            },
+        };
+        Some(KubeVersion { major, stability })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum VersionPattern {
+    Glob(String),
+    /// All of the comparisons must hold, e.g. `>=v1beta1,<v2`
+    Range(Vec<(Vec<Ordering>, KubeVersion)>),
+}
+
+impl VersionPattern {
+    fn parse(pattern: &str) -> Result<VersionPattern, String> {
+        if !pattern.starts_with(['<', '>', '=']) {
+            return Ok(VersionPattern::Glob(pattern.to_string()));
+        }
+        pattern.split(',')
+            .map(|constraint| {
+                let constraint = constraint.trim();
+                let (orderings, version) = [
+                    (">=", vec![Ordering::Greater, Ordering::Equal]),
+                    ("<=", vec![Ordering::Less, Ordering::Equal]),
+                    (">", vec![Ordering::Greater]),
+                    ("<", vec![Ordering::Less]),
+                    ("=", vec![Ordering::Equal]),
+                ].into_iter()
+                    .find_map(|(operator, orderings)| constraint.strip_prefix(operator).map(|version| (orderings, version)))
+                    .ok_or_else(|| format!("Invalid version constraint '{}'", constraint))?;
+                KubeVersion::parse(version.trim())
+                    .map(|version| (orderings, version))
+                    .ok_or_else(|| format!("Invalid version '{}' in version range '{}'", version, pattern))
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(VersionPattern::Range)
+    }
+
+    fn matches(&self, version: &str) -> bool {
+        match self {
+            VersionPattern::Glob(glob) => glob_matches(glob, version),
+            VersionPattern::Range(constraints) => KubeVersion::parse(version)
+                .map(|version| constraints.iter().all(|(orderings, bound)| orderings.contains(&version.cmp(bound))))
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// The `gvk` template setting. Lists that are left out match anything.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GvkRules {
+    /// Use `""` for the core group
+    groups: Option<Vec<String>>,
+    /// Globs or ranges such as `>=v1beta1,<v2`
+    versions: Option<Vec<String>>,
+    kinds: Option<Vec<String>>,
+}
+
+/// Matches a resource's group, version and kind. Group, kind and version globs may use `*` and `?`.
+#[derive(Debug, Clone)]
+pub struct GvkMatcher {
+    groups: Vec<String>,
+    versions: Vec<VersionPattern>,
+    kinds: Vec<String>,
+}
+
+/// Splits an apiVersion into group and version, core resources having the group `""`
+fn split_api_version(api_version: &str) -> (&str, &str) {
+    match api_version.rsplit_once('/') {
+        Some((group, version)) => (group, version),
+        None if api_version == "*" => ("*", "*"),
+        None => ("", api_version),
+    }
+}
+
+impl GvkMatcher {
+    /// Matches the template's own apiVersion and kind, which may be globs such as `apps/*`
+    pub fn from_template(api_version: &str, kind: &str) -> GvkMatcher {
+        let (group, version) = split_api_version(api_version);
+        GvkMatcher {
+            groups: vec![group.to_string()],
+            versions: vec![VersionPattern::Glob(version.to_string())],
+            kinds: vec![kind.to_string()],
+        }
+    }
+
+    pub fn from_rules(rules: GvkRules) -> Result<GvkMatcher, String> {
+        let any = || vec![String::from("*")];
+        Ok(GvkMatcher {
+            groups: rules.groups.unwrap_or_else(any),
+            versions: rules.versions.unwrap_or_else(any).iter()
+                .map(|version| VersionPattern::parse(version))
+                .collect::<Result<Vec<_>, String>>()?,
+            kinds: rules.kinds.unwrap_or_else(any),
+        })
+    }
+
+    pub fn matches(&self, api_version: &str, kind: &str) -> bool {
+        let (group, version) = split_api_version(api_version);
+        self.groups.iter().any(|pattern| glob_matches(pattern, group)) &&
+        self.versions.iter().any(|pattern| pattern.matches(version)) &&
+        self.kinds.iter().any(|pattern| glob_matches(pattern, kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GvkMatcher, GvkRules, KubeVersion};
+
+    fn rules(yaml: &str) -> GvkMatcher {
+        GvkMatcher::from_rules(serde_yaml::from_str::<GvkRules>(yaml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn orders_versions() {
+        let versions = ["v1alpha1", "v1alpha2", "v1beta1", "v1beta2", "v1", "v2alpha1", "v2", "v10"];
+        let parsed: Vec<KubeVersion> = versions.iter().map(|version| KubeVersion::parse(version).unwrap()).collect();
+        assert!(parsed.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", parsed);
+        assert_eq!(None, KubeVersion::parse("1"));
+        assert_eq!(None, KubeVersion::parse("v1gamma1"));
+        assert_eq!(None, KubeVersion::parse("v1beta"));
+    }
+
+    #[test]
+    fn template_api_version_and_kind() {
+        let exact = GvkMatcher::from_template("apps/v1", "Deployment");
+        assert!(exact.matches("apps/v1", "Deployment"));
+        assert!(!exact.matches("apps/v1beta2", "Deployment"));
+        assert!(!exact.matches("v1", "Deployment"));
+        let core = GvkMatcher::from_template("v1", "Pod");
+        assert!(core.matches("v1", "Pod"));
+        assert!(!core.matches("example.com/v1", "Pod"));
+        let any_version = GvkMatcher::from_template("apps/*", "*");
+        assert!(any_version.matches("apps/v1beta2", "StatefulSet"));
+        assert!(!any_version.matches("batch/v1", "Job"));
+        assert!(GvkMatcher::from_template("*", "*").matches("v1", "Pod"));
+    }
+
+    #[test]
+    fn rules_with_lists_and_wildcards() {
+        let workloads = rules(r#"
+        groups: [ apps ]
+        kinds: [ Deployment, StatefulSet, DaemonSet ]
+        "#);
+        assert!(workloads.matches("apps/v1", "Deployment"));
+        assert!(workloads.matches("apps/v1beta2", "DaemonSet"));
+        assert!(!workloads.matches("apps/v1", "ReplicaSet"));
+        assert!(!workloads.matches("v1", "Deployment"));
+        let networking = rules(r#"groups: [ "*.k8s.io", "" ]"#);
+        assert!(networking.matches("networking.k8s.io/v1", "Ingress"));
+        assert!(networking.matches("v1", "Service"));
+        assert!(!networking.matches("example.com/v1", "Widget"));
+    }
+
+    #[test]
+    fn version_ranges() {
+        let matcher = rules(r#"versions: [ ">=v1beta2,<v2", v3* ]"#);
+        assert!(!matcher.matches("apps/v1beta1", "Deployment"));
+        assert!(matcher.matches("apps/v1beta2", "Deployment"));
+        assert!(matcher.matches("apps/v1", "Deployment"));
+        assert!(matcher.matches("apps/v2alpha1", "Deployment"), "v2 pre-releases come before v2");
+        assert!(!matcher.matches("apps/v2", "Deployment"));
+        assert!(matcher.matches("apps/v3alpha1", "Deployment"));
+        assert!(!matcher.matches("apps/latest", "Deployment"));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        let invalid = |versions: &str| GvkMatcher::from_rules(serde_yaml::from_str(&format!("versions: [ '{}' ]", versions)).unwrap()).err().unwrap();
+        assert!(invalid(">=1").contains("'1'"));
+        assert!(invalid(">=v1,~v2").contains("~v2"));
+    }
+
+}
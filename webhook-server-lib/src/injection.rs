@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::resource::ObjectMeta;
+
+/// Keys, looked for in both annotations and labels, letting workload and namespace owners
+/// control injection. None are used unless configured in the templates file's `injection:`.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Injection {
+    /// Objects, or namespaces, with this set to `"true"` get no templates
+    skip_key: Option<String>,
+    /// If set only namespaces labelled with this set to `"true"` get templates.
+    /// Cluster scoped objects, having no namespace to opt in, aren't affected.
+    namespace_opt_in_key: Option<String>,
+    /// Objects with this set, e.g. to `logging,metrics`, only get the templates named
+    templates_key: Option<String>,
+}
+
+fn get<'a>(meta: Option<&'a ObjectMeta>, key: &str) -> Option<&'a String> {
+    meta.and_then(|meta| {
+        meta.annotations.as_ref().and_then(|annotations| annotations.get(key))
+            .or_else(|| meta.labels.as_ref().and_then(|labels| labels.get(key)))
+    })
+}
+
+fn is_true(value: Option<&String>) -> bool {
+    value.map(|value| value.eq_ignore_ascii_case("true")).unwrap_or(false)
+}
+
+impl Injection {
+    /// Why the object shouldn't get any templates, if it shouldn't
+    pub fn skip_reason(&self, meta: Option<&ObjectMeta>, namespace_labels: Option<&BTreeMap<String, String>>) -> Option<String> {
+        if let Some(key) = self.skip_key.as_ref() {
+            if is_true(get(meta, key)) {
+                return Some(format!("object has {}", key));
+            }
+            if is_true(namespace_labels.and_then(|labels| labels.get(key))) {
+                return Some(format!("namespace has {}", key));
+            }
+        }
+        let namespaced = meta.map(|meta| meta.namespace.is_some()).unwrap_or(false);
+        if let Some(key) = self.namespace_opt_in_key.as_ref().filter(|_| namespaced) {
+            if !is_true(namespace_labels.and_then(|labels| labels.get(key))) {
+                return Some(format!("namespace hasn't opted in with {}", key));
+            }
+        }
+        None
+    }
+
+    /// The names of the templates the object asks for, if it names any
+    pub fn requested_templates(&self, meta: Option<&ObjectMeta>) -> Option<Vec<String>> {
+        self.templates_key.as_ref()
+            .and_then(|key| get(meta, key))
+            .map(|names| names.split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::resource::ObjectMeta;
+
+    use super::Injection;
+
+    fn injection() -> Injection {
+        serde_yaml::from_str(r#"
+        skipKey: inject.example.com/skip
+        namespaceOptInKey: inject.example.com/enabled
+        templatesKey: inject.example.com/templates
+        "#).unwrap()
+    }
+
+    fn meta(annotations: &[(&str, &str)], labels: &[(&str, &str)]) -> ObjectMeta {
+        let map = |pairs: &[(&str, &str)]| Some(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect());
+        let mut meta = ObjectMeta::default();
+        meta.annotations = map(annotations);
+        meta.labels = map(labels);
+        meta
+    }
+
+    fn namespaced() -> ObjectMeta {
+        let mut meta = ObjectMeta::default();
+        meta.namespace = Some(String::from("team-a"));
+        meta
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn nothing_skipped_by_default() {
+        let injection = Injection::default();
+        assert_eq!(None, injection.skip_reason(Some(&meta(&[("inject.example.com/skip", "true")], &[])), None));
+        assert_eq!(None, injection.requested_templates(Some(&meta(&[("inject.example.com/templates", "a")], &[]))));
+    }
+
+    #[test]
+    fn skips_objects_and_namespaces() {
+        let injection = injection();
+        let enabled = labels(&[("inject.example.com/enabled", "true")]);
+        assert_eq!(None, injection.skip_reason(Some(&namespaced()), Some(&enabled)));
+        assert!(injection.skip_reason(Some(&meta(&[("inject.example.com/skip", "true")], &[])), Some(&enabled)).is_some());
+        assert!(injection.skip_reason(Some(&meta(&[], &[("inject.example.com/skip", "True")])), Some(&enabled)).is_some());
+        assert_eq!(None, injection.skip_reason(Some(&meta(&[("inject.example.com/skip", "false")], &[])), Some(&enabled)));
+        let skipped = labels(&[("inject.example.com/enabled", "true"), ("inject.example.com/skip", "true")]);
+        assert!(injection.skip_reason(Some(&namespaced()), Some(&skipped)).unwrap().contains("namespace"));
+        assert!(injection.skip_reason(Some(&namespaced()), Some(&labels(&[]))).unwrap().contains("opted in"));
+        assert!(injection.skip_reason(Some(&namespaced()), None).is_some());
+    }
+
+    #[test]
+    fn opt_in_doesnt_apply_to_cluster_scoped_objects() {
+        let injection = injection();
+        assert_eq!(None, injection.skip_reason(None, None));
+        assert_eq!(None, injection.skip_reason(Some(&meta(&[], &[])), None));
+        assert!(injection.skip_reason(Some(&meta(&[("inject.example.com/skip", "true")], &[])), None).is_some());
+    }
+
+    #[test]
+    fn requested_templates() {
+        let injection = injection();
+        assert_eq!(Some(vec![String::from("logging"), String::from("metrics")]),
+            injection.requested_templates(Some(&meta(&[("inject.example.com/templates", "logging, metrics,")], &[]))));
+        assert_eq!(None, injection.requested_templates(Some(&meta(&[], &[]))));
+    }
+
+}
@@ -1,46 +1,88 @@
+use std::collections::BTreeMap;
 use std::net::{SocketAddr, IpAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
+use arc_swap::ArcSwap;
 use http_body_util::{Full, combinators::BoxBody, Empty, BodyExt};
-use hyper::{Request, Response, body::{Bytes, Incoming, Frame}, server::conn::http1, service::service_fn, Method, StatusCode};
+use hyper::{Request, Response, body::{Bytes, Incoming}, service::service_fn, Method, StatusCode, header};
+use hyper_util::{rt::{TokioExecutor, TokioIo}, server::conn::auto};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 
+pub mod admission;
+mod conditions;
 pub mod config;
-pub mod templates;
+pub mod crd;
+mod gvk;
+mod injection;
+mod loader;
+mod patch;
+mod policies;
+mod predicate;
+mod render;
 mod resource;
+mod selector;
+mod sources;
+mod substitute;
+pub mod templates;
+mod tls;
+mod watch;
 
+use admission::AdmissionReview;
 use config::Args;
 use templates::Templates;
 
-async fn echo(req: Request<Incoming>) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+async fn route(req: Request<Incoming>, templates: Arc<ArcSwap<Templates>>, routes: Routes) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/") => Ok(Response::new(full(
-            "Try POSTing data to /echo"
+            "Try POSTing an AdmissionReview to /mutate or /validate"
         ))),
-        (&Method::POST, "/echo") => {
-            Ok(Response::new(req.into_body().boxed()))
+        (&Method::POST, "/mutate") => review(req, |review| review.mutate(&templates.load())).await,
+        (&Method::POST, "/validate") => review(req, |review| review.validate(&templates.load())).await,
+        (&Method::POST, path) if routes.contains_key(path) => {
+            let templates = routes[path].clone();
+            review(req, |review| review.mutate(&templates.load())).await
         },
-        (&Method::POST, "/echo/uppercase") => {
-            let frame_stream = req.into_body().map_frame(|frame| {
-                let frame = if let Ok(data) = frame.into_data() {
-                    data.iter()
-                        .map(|byte| byte.to_ascii_uppercase())
-                        .collect::<Bytes>()
-                } else {
-                    Bytes::new()
-                };
-                Frame::data(frame)
-            });
-            Ok(Response::new(frame_stream.boxed()))
+        (&Method::POST, path) if path.strip_suffix("/validate").is_some_and(|route| routes.contains_key(route)) => {
+            let templates = routes[&path[..path.len() - "/validate".len()]].clone();
+            review(req, |review| review.validate(&templates.load())).await
         },
+        _ => Ok(with_status(empty(), StatusCode::NOT_FOUND)),
+    }
+}
 
-        _ => {
-            let mut not_found = Response::new(empty());
-            *not_found.status_mut() = StatusCode::NOT_FOUND;
-            Ok(not_found)
+/// Reads the AdmissionReview in the request and responds with the review `respond` makes of it
+async fn review<F>(req: Request<Incoming>, respond: F) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error>
+    where F: FnOnce(AdmissionReview) -> Result<AdmissionReview, String> {
+    let body = req.into_body().collect().await?.to_bytes();
+    let review = match AdmissionReview::from_json(&body) {
+        Ok(review) => review,
+        Err(err) => {
+            log::warn!("Failed to read AdmissionReview: {}", err);
+            return Ok(with_status(full(err.to_string()), StatusCode::BAD_REQUEST));
+        }
+    };
+    match respond(review).and_then(|response| response.to_json().map_err(|err| err.to_string())) {
+        Ok(json) => {
+            let mut response = Response::new(full(json));
+            response.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+            Ok(response)
+        },
+        Err(err) => {
+            log::warn!("Failed to process AdmissionReview: {}", err);
+            Ok(with_status(full(err), StatusCode::BAD_REQUEST))
         }
     }
 }
 
+fn with_status(body: BoxBody<Bytes, hyper::Error>, status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
+
 fn empty() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
@@ -53,28 +95,88 @@ fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
         .boxed()
 }
 
-pub async fn run_server(args: Args, _templates: Templates) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// How long a client has to complete the TLS handshake before its connection is dropped
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The templates served at each path given with `--route`
+type Routes = Arc<BTreeMap<String, Arc<ArcSwap<Templates>>>>;
+
+async fn serve_connection<S>(stream: S, templates: Arc<ArcSwap<Templates>>, routes: Routes)
+where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    let service = service_fn(move |req| route(req, templates.clone(), routes.clone()));
+    if let Err(err) = auto::Builder::new(TokioExecutor::new())
+        .serve_connection(TokioIo::new(stream), service)
+        .await
+    {
+        log::error!("Error serving connection: {:?}", err);
+    }
+}
+
+pub async fn run_server(args: Args, templates: Templates) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     log::info!("Setting up server on {}:{}", args.address, args.port);
     let ip_addr: IpAddr = args.address.parse().unwrap();
     let addr = SocketAddr::new(ip_addr, args.port);
 
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert_file), Some(key_file)) => {
+            log::info!("Serving HTTPS with certificate {} and key {}", cert_file, key_file);
+            Some(tls::reloading_config(cert_file, key_file, Duration::from_millis(args.reload_interval_ms))?)
+        },
+        _ => {
+            log::warn!("No TLS certificate configured - serving plain HTTP");
+            None
+        },
+    };
+
     let listener = TcpListener::bind(addr).await?;
+    let interval = Duration::from_millis(args.reload_interval_ms);
+    let templates = if args.template_resources {
+        let client = match args.kube_api_url.as_ref() {
+            Some(url) => crd::ApiClient::new(url),
+            None => crd::ApiClient::in_cluster()?,
+        };
+        templates.watch_with_resources(&args.templates_file, interval, client)
+    } else {
+        templates.watch(&args.templates_file, interval)
+    };
+    let routes = load_routes(&args)?;
 
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, remote) = listener.accept().await?;
+        let templates = templates.clone();
+        let routes = routes.clone();
+        let acceptor = tls_config.as_ref().map(|config| TlsAcceptor::from(config.load_full()));
 
         tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(stream, service_fn(echo))
-                .await
-            {
-                log::error!("Error serving connection: {:?}", err);
+            match acceptor {
+                Some(acceptor) => match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => serve_connection(tls_stream, templates, routes).await,
+                    Ok(Err(err)) => log::error!("TLS handshake with {} failed: {:?}", remote, err),
+                    Err(_) => log::error!("TLS handshake with {} not completed within {:?} - dropping the connection", remote, TLS_HANDSHAKE_TIMEOUT),
+                },
+                None => serve_connection(stream, templates, routes).await,
             }
         });
     }
 }
 
+/// Loads, and watches, the templates of every route
+fn load_routes(args: &Args) -> Result<Routes, String> {
+    let mut routes = BTreeMap::new();
+    for route in args.routes.iter() {
+        if routes.contains_key(&route.path) {
+            return Err(format!("Path {} is given more than one route", route.path));
+        }
+        let templates = Templates::from_file(&route.templates_file)
+            .map_err(|err| format!("Failed to load templates for {} from {}: {}", route.path, route.templates_file, err))?;
+        log::info!("Serving {} template(s) from {} at {}", templates.len(), route.templates_file, route.path);
+        routes.insert(route.path.clone(), templates.watch(&route.templates_file, Duration::from_millis(args.reload_interval_ms)));
+    }
+    Ok(Arc::new(routes))
+}
+
 pub async fn server_main(args: Args) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let templates = templates::Templates::from_file(&args.templates_file).expect("Failed to load templates");
+    let templates = templates::Templates::from_file(&args.templates_file)
+        .map_err(|err| format!("Failed to load templates from {}: {}", args.templates_file, err))?;
     run_server(args, templates).await
 }
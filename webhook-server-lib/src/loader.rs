@@ -0,0 +1,135 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::selector::glob_matches;
+
+/// A YAML document of templates and where it came from, for errors and logs
+#[derive(Clone)]
+pub struct Document {
+    /// The file, and the document within it if there's more than one, `None` for text given directly
+    pub source: Option<String>,
+    pub value: serde_yaml::Value,
+    /// Whether `$valueFrom` is resolved. It reads the webhook's own environment and files, so is
+    /// only for the webhook's own templates files, not for resources others can write.
+    pub trusted: bool,
+}
+
+/// Splits the YAML into its `---` separated documents, leaving out empty ones
+pub fn documents(file: Option<&str>, yaml: &str) -> Result<Vec<Document>, String> {
+    let values = serde_yaml::Deserializer::from_str(yaml)
+        .map(serde_yaml::Value::deserialize)
+        .collect::<Result<Vec<serde_yaml::Value>, serde_yaml::Error>>()
+        .map_err(|err| match file {
+            Some(file) => format!("{}: {}", file, err),
+            None => err.to_string(),
+        })?;
+    let several = values.len() > 1;
+    Ok(values.into_iter()
+        .enumerate()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(index, value)| Document {
+            source: match (file, several) {
+                (Some(file), true) => Some(format!("{} (document {})", file, index + 1)),
+                (Some(file), false) => Some(file.to_string()),
+                (None, true) => Some(format!("document {}", index + 1)),
+                (None, false) => None,
+            },
+            value,
+            trusted: true,
+        })
+        .collect())
+}
+
+/// The files `path` refers to, in the order they're loaded: the file itself, every `*.yaml` and `*.yml`
+/// file in a directory, or the files matching a glob such as `templates/*-baseline.yaml`, both sorted by name.
+/// Hidden files are left out, as are the `..data` links of mounted ConfigMaps.
+pub fn files(path: &str) -> Result<Vec<String>, String> {
+    let (dir, pattern) = if Path::new(path).is_dir() {
+        (Path::new(path), None)
+    } else if path.contains(['*', '?']) {
+        let path = Path::new(path);
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        if dir.to_string_lossy().contains(['*', '?']) {
+            return Err(format!("Only file names can be globs, not directories: {}", path.display()));
+        }
+        (dir, path.file_name().and_then(|name| name.to_str()))
+    } else {
+        return Ok(vec![path.to_string()]);
+    };
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|err| format!("Unable to read {}: {}", dir.display(), err))? {
+        let entry = entry.map_err(|err| format!("Unable to read {}: {}", dir.display(), err))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let included = match pattern {
+            Some(pattern) => glob_matches(pattern, &name),
+            None => name.ends_with(".yaml") || name.ends_with(".yml"),
+        };
+        //Metadata rather than the entry's file type, to follow symlinks
+        if included && !name.starts_with('.') && std::fs::metadata(entry.path()).map(|meta| meta.is_file()).unwrap_or(false) {
+            files.push(entry.path().to_string_lossy().to_string());
+        }
+    }
+    files.sort();
+    if files.is_empty() {
+        return Err(format!("No template files found in {}", path));
+    }
+    Ok(files)
+}
+
+/// Every document of every file `path` refers to
+pub fn load(path: &str) -> Result<Vec<Document>, String> {
+    let mut loaded = Vec::new();
+    for file in files(path)? {
+        let yaml = std::fs::read_to_string(&file).map_err(|err| format!("Unable to read {}: {}", file, err))?;
+        loaded.extend(documents(Some(&file), &yaml)?);
+    }
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{documents, files, load};
+
+    fn sources(yaml: &str, file: Option<&str>) -> Vec<Option<String>> {
+        documents(file, yaml).unwrap().into_iter().map(|document| document.source).collect()
+    }
+
+    #[test]
+    fn splits_documents() {
+        assert_eq!(vec![None], sources("templates: []", None));
+        assert_eq!(vec![Some(String::from("a.yaml"))], sources("templates: []", Some("a.yaml")));
+        let yaml = "templates: []\n---\n---\npolicies: []\n";
+        assert_eq!(vec![Some(String::from("a.yaml (document 1)")), Some(String::from("a.yaml (document 3)"))], sources(yaml, Some("a.yaml")));
+        assert_eq!(vec![Some(String::from("document 1")), Some(String::from("document 3"))], sources(yaml, None));
+        assert!(documents(Some("a.yaml"), "templates: [").err().unwrap().starts_with("a.yaml: "));
+    }
+
+    #[test]
+    fn finds_files_in_directories_and_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b-team.yaml", "a-baseline.yaml", "c-team.yml", "notes.txt", ".hidden.yaml"] {
+            std::fs::write(dir.path().join(name), "templates: []").unwrap();
+        }
+        std::fs::create_dir(dir.path().join("..data")).unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        assert_eq!(vec![path("a-baseline.yaml"), path("b-team.yaml"), path("c-team.yml")], files(&path("")).unwrap());
+        assert_eq!(vec![path("b-team.yaml"), path("c-team.yml")], files(&path("*-team.*")).unwrap());
+        assert_eq!(vec![path("notes.txt")], files(&path("notes.txt")).unwrap());
+        assert!(files(&path("*.json")).is_err());
+        assert!(files(&path("*/templates.yaml")).is_err());
+    }
+
+    #[test]
+    fn loads_every_document() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.yaml"), "templates: []\n---\npolicies: []").unwrap();
+        std::fs::write(dir.path().join("b.yaml"), "templates: []").unwrap();
+        let loaded = load(dir.path().to_str().unwrap()).unwrap();
+        let names: Vec<String> = loaded.into_iter()
+            .map(|document| document.source.unwrap().rsplit('/').next().unwrap().to_string())
+            .collect();
+        assert_eq!(vec!["a.yaml (document 1)", "a.yaml (document 2)", "b.yaml"], names);
+    }
+
+}
@@ -0,0 +1,223 @@
+use serde::Serialize;
+
+use crate::resource::Resource;
+
+/// A single RFC 6902 JSON Patch operation
+#[derive(Debug, PartialEq, Serialize, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: serde_json::Value },
+    Replace { path: String, value: serde_json::Value },
+    Remove { path: String },
+}
+
+/// Escapes a key for use as a JSON Pointer reference token (RFC 6901)
+pub fn escape_key(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Produces the operations that turn `original` into `mutated` when applied in order
+pub fn diff(original: &serde_json::Value, mutated: &serde_json::Value) -> Vec<PatchOperation> {
+    let mut operations = Vec::new();
+    diff_values("", original, mutated, &mut operations);
+    operations
+}
+
+pub fn diff_resources(original: &Resource<serde_json::Value>, mutated: &Resource<serde_json::Value>) -> serde_json::Result<Vec<PatchOperation>> {
+    Ok(diff(&serde_json::to_value(original)?, &serde_json::to_value(mutated)?))
+}
+
+fn diff_values(path: &str, original: &serde_json::Value, mutated: &serde_json::Value, operations: &mut Vec<PatchOperation>) {
+    match (original, mutated) {
+        (serde_json::Value::Object(map1), serde_json::Value::Object(map2)) =>
+            diff_objects(path, map1, map2, operations),
+        (serde_json::Value::Array(vec1), serde_json::Value::Array(vec2)) =>
+            diff_arrays(path, vec1, vec2, operations),
+        (_, _) if original != mutated =>
+            operations.push(PatchOperation::Replace { path: path.to_string(), value: mutated.clone() }),
+        (_, _) => (),
+    }
+}
+
+fn diff_objects(path: &str, original: &serde_json::Map<String, serde_json::Value>, mutated: &serde_json::Map<String, serde_json::Value>, operations: &mut Vec<PatchOperation>) {
+    //Removals first, then changes to existing keys, then additions
+    for key in original.keys().filter(|key| !mutated.contains_key(*key)) {
+        operations.push(PatchOperation::Remove { path: child_path(path, key) });
+    }
+    for (key, v1) in original.iter() {
+        if let Some(v2) = mutated.get(key) {
+            diff_values(&child_path(path, key), v1, v2, operations);
+        }
+    }
+    for (key, v2) in mutated.iter().filter(|(key, _)| !original.contains_key(*key)) {
+        operations.push(PatchOperation::Add { path: child_path(path, key), value: v2.clone() });
+    }
+}
+
+/// Items common to the start and end of both arrays are kept in place, so items added or
+/// removed at either end (or in one place in the middle) only produce operations for those items.
+fn diff_arrays(path: &str, original: &[serde_json::Value], mutated: &[serde_json::Value], operations: &mut Vec<PatchOperation>) {
+    let max_common = original.len().min(mutated.len());
+    let prefix = original.iter().zip(mutated.iter())
+        .take_while(|(v1, v2)| v1 == v2)
+        .count();
+    let suffix = original.iter().rev().zip(mutated.iter().rev())
+        .take(max_common - prefix)
+        .take_while(|(v1, v2)| v1 == v2)
+        .count();
+    let original_middle = &original[prefix..original.len() - suffix];
+    let mutated_middle = &mutated[prefix..mutated.len() - suffix];
+    let paired = original_middle.len().min(mutated_middle.len());
+    for (index, (v1, v2)) in original_middle.iter().zip(mutated_middle.iter()).enumerate() {
+        diff_values(&child_path(path, &(prefix + index).to_string()), v1, v2, operations);
+    }
+    //Remove from the back so the earlier indexes stay valid
+    for index in (paired..original_middle.len()).rev() {
+        operations.push(PatchOperation::Remove { path: child_path(path, &(prefix + index).to_string()) });
+    }
+    for (index, value) in mutated_middle.iter().enumerate().skip(paired) {
+        operations.push(PatchOperation::Add { path: child_path(path, &(prefix + index).to_string()), value: value.clone() });
+    }
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    format!("{}/{}", path, escape_key(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::resource::Resource;
+
+    use super::{diff, escape_key, PatchOperation};
+
+    fn apply(original: &serde_json::Value, operations: &[PatchOperation]) -> serde_json::Value {
+        let patch: json_patch::Patch = serde_json::from_value(serde_json::to_value(operations).unwrap()).unwrap();
+        let mut patched = original.clone();
+        json_patch::patch(&mut patched, &patch).unwrap();
+        patched
+    }
+
+    #[test]
+    fn escapes_pointer_characters() {
+        assert_eq!("io.kube.label1", escape_key("io.kube.label1"));
+        assert_eq!("app.kubernetes.io~1name", escape_key("app.kubernetes.io/name"));
+        assert_eq!("a~0b~1c", escape_key("a~b/c"));
+    }
+
+    #[test]
+    fn no_operations_for_equal_values() {
+        let value = serde_json::json!({ "a": [1, 2, { "b": "c" }] });
+        assert_eq!(Vec::<PatchOperation>::new(), diff(&value, &value));
+    }
+
+    #[test]
+    fn serializes_as_json_patch() {
+        let operations = diff(
+            &serde_json::json!({ "a": 1, "b": 2 }),
+            &serde_json::json!({ "a": 3, "c": 4 }),
+        );
+        let expected = serde_json::json!([
+            { "op": "remove", "path": "/b" },
+            { "op": "replace", "path": "/a", "value": 3 },
+            { "op": "add", "path": "/c", "value": 4 },
+        ]);
+        assert_eq!(expected, serde_json::to_value(operations).unwrap());
+    }
+
+    #[test]
+    fn adds_escaped_keys() {
+        let operations = diff(
+            &serde_json::json!({ "metadata": { "annotations": { "io.kube.label1": "silly" } } }),
+            &serde_json::json!({ "metadata": { "annotations": { "io.kube.label1": "silly", "io.kube/label~2": "sensible" } } }),
+        );
+        let expected = vec![PatchOperation::Add {
+            path: String::from("/metadata/annotations/io.kube~1label~02"),
+            value: serde_json::json!("sensible"),
+        }];
+        assert_eq!(expected, operations);
+    }
+
+    #[test]
+    fn only_patches_changed_array_items() {
+        let original = serde_json::json!({ "containers": [ { "name": "a" }, { "name": "b" } ] });
+        let mutated = serde_json::json!({ "containers": [ { "name": "a", "env": [] }, { "name": "b" }, { "name": "c" } ] });
+        let expected = vec![
+            PatchOperation::Add { path: String::from("/containers/0/env"), value: serde_json::json!([]) },
+            PatchOperation::Add { path: String::from("/containers/2"), value: serde_json::json!({ "name": "c" }) },
+        ];
+        assert_eq!(expected, diff(&original, &mutated));
+    }
+
+    #[test]
+    fn prepends_without_replacing_existing_items() {
+        let original = serde_json::json!([ "b", "c" ]);
+        let mutated = serde_json::json!([ "a", "b", "c" ]);
+        let expected = vec![
+            PatchOperation::Add { path: String::from("/0"), value: serde_json::json!("a") },
+        ];
+        assert_eq!(expected, diff(&original, &mutated));
+    }
+
+    #[test]
+    fn removes_from_the_back() {
+        let original = serde_json::json!([ "a", "b", "c", "d" ]);
+        let mutated = serde_json::json!([ "a", "d" ]);
+        let expected = vec![
+            PatchOperation::Remove { path: String::from("/2") },
+            PatchOperation::Remove { path: String::from("/1") },
+        ];
+        assert_eq!(expected, diff(&original, &mutated));
+        assert_eq!(mutated, apply(&original, &diff(&original, &mutated)));
+    }
+
+    #[test]
+    fn replaces_root_of_different_type() {
+        let expected = vec![PatchOperation::Replace { path: String::new(), value: serde_json::json!([1]) }];
+        assert_eq!(expected, diff(&serde_json::json!({ "a": 1 }), &serde_json::json!([1])));
+    }
+
+    fn arb_json() -> impl Strategy<Value = serde_json::Value> {
+        let leaf = prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::Bool),
+            (0..4i64).prop_map(|n| serde_json::json!(n)),
+            "[a-c~/.]{0,3}".prop_map(serde_json::Value::String),
+        ];
+        leaf.prop_recursive(4, 32, 4, |inner| prop_oneof![
+            prop::collection::vec(inner.clone(), 0..4).prop_map(serde_json::Value::Array),
+            prop::collection::btree_map("[a-c~/.]{1,3}", inner, 0..4)
+                .prop_map(|map| serde_json::Value::Object(map.into_iter().collect())),
+        ])
+    }
+
+    fn arb_resource() -> impl Strategy<Value = Resource<serde_json::Value>> {
+        (prop::option::of(arb_json()), prop::option::of(arb_json()), prop::collection::btree_map("[a-c.~/]{1,3}", "[a-c]{0,2}", 0..3))
+            .prop_map(|(spec, data, labels)| {
+                Resource::from_json(&serde_json::json!({
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "labels": labels },
+                    "spec": spec,
+                    "data": data,
+                }).to_string()).unwrap()
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn patch_reproduces_mutated_value(original in arb_json(), mutated in arb_json()) {
+            prop_assert_eq!(&mutated, &apply(&original, &diff(&original, &mutated)));
+        }
+
+        #[test]
+        fn patch_reproduces_merged_resource(original in arb_resource(), template in arb_resource()) {
+            let merged = original.merge(&template);
+            let operations = super::diff_resources(&original, &merged).unwrap();
+            let patched = apply(&serde_json::to_value(&original).unwrap(), &operations);
+            prop_assert_eq!(serde_json::to_value(&merged).unwrap(), patched);
+        }
+    }
+
+}
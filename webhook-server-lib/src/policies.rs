@@ -0,0 +1,200 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::gvk::{GvkMatcher, GvkRules};
+use crate::resource::Resource;
+use crate::selector::{glob_matches, GlobFilter};
+
+/// A policy as written in the templates file's `policies:`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PolicyConfig {
+    name: String,
+    /// The objects checked, everything if left out
+    #[serde(default)]
+    gvk: GvkRules,
+    namespaces: Option<GlobFilter>,
+    /// Labels that must be present
+    #[serde(default)]
+    required_labels: Vec<String>,
+    /// Registries, or globs of them, that container images can't come from. Images without
+    /// a registry are from `docker.io`.
+    #[serde(default)]
+    forbidden_registries: Vec<String>,
+    /// Resources every container must have a limit for, e.g. `[ cpu, memory ]`.
+    /// Ephemeral containers can't have resources, so aren't checked.
+    #[serde(default)]
+    required_resource_limits: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct Policy {
+    pub name: String,
+    gvk: GvkMatcher,
+    namespaces: Option<GlobFilter>,
+    required_labels: Vec<String>,
+    forbidden_registries: Vec<String>,
+    required_resource_limits: Vec<String>,
+}
+
+impl Policy {
+    pub fn construct(config: PolicyConfig) -> Result<Policy, String> {
+        Ok(Policy {
+            gvk: GvkMatcher::from_rules(config.gvk).map_err(|err| format!("Policy {}: {}", config.name, err))?,
+            name: config.name,
+            namespaces: config.namespaces,
+            required_labels: config.required_labels,
+            forbidden_registries: config.forbidden_registries,
+            required_resource_limits: config.required_resource_limits,
+        })
+    }
+
+    fn applies_to(&self, resource: &Resource<Value>) -> bool {
+        self.gvk.matches(&resource.api_version, &resource.kind) &&
+        self.namespaces.as_ref().map(|namespaces| {
+            namespaces.matches(resource.metadata.as_ref().and_then(|meta| meta.namespace.as_deref()))
+        }).unwrap_or(true)
+    }
+
+    /// Every way the resource breaks the policy
+    pub fn violations(&self, resource: &Resource<Value>) -> Vec<String> {
+        if !self.applies_to(resource) {
+            return vec![];
+        }
+        let mut violations = Vec::new();
+        let labels = resource.metadata.as_ref().and_then(|meta| meta.labels.as_ref());
+        for label in self.required_labels.iter().filter(|label| !labels.map(|labels| labels.contains_key(*label)).unwrap_or(false)) {
+            violations.push(format!("missing required label {}", label));
+        }
+        let object = match self.forbidden_registries.is_empty() && self.required_resource_limits.is_empty() {
+            true => Value::Null,
+            false => serde_json::to_value(resource).unwrap_or_default(),
+        };
+        for (field, container) in containers(&object) {
+            let name = container.get("name").and_then(Value::as_str).unwrap_or("?");
+            if let Some(image) = container.get("image").and_then(Value::as_str) {
+                let registry = registry(image);
+                if self.forbidden_registries.iter().any(|forbidden| glob_matches(forbidden, registry)) {
+                    violations.push(format!("container {} uses image {} from forbidden registry {}", name, image, registry));
+                }
+            }
+            for limit in self.required_resource_limits.iter().filter(|_| field != "ephemeralContainers") {
+                if container.pointer(&format!("/resources/limits/{}", crate::patch::escape_key(limit))).is_none() {
+                    violations.push(format!("container {} has no {} limit", name, limit));
+                }
+            }
+        }
+        violations.into_iter().map(|violation| format!("{}: {}", self.name, violation)).collect()
+    }
+}
+
+/// The containers of a Pod, or of the pod template of a workload such as a Deployment or CronJob,
+/// each with the field it's in
+fn containers(object: &Value) -> impl Iterator<Item = (&'static str, &Value)> {
+    ["/spec", "/spec/template/spec", "/spec/jobTemplate/spec/template/spec"].into_iter()
+        .filter_map(|path| object.pointer(path))
+        .flat_map(|pod_spec| ["containers", "initContainers", "ephemeralContainers"].into_iter()
+            .filter_map(|field| pod_spec.get(field).and_then(Value::as_array).map(|containers| (field, containers))))
+        .flat_map(|(field, containers)| containers.iter().map(move |container| (field, container)))
+}
+
+/// The registry of an image reference, as Docker reads it: the first part if it looks like a host
+fn registry(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((first, _)) if first.contains('.') || first.contains(':') || first == "localhost" => first,
+        _ => "docker.io",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resource::Resource;
+
+    use super::{registry, Policy};
+
+    fn policy(yaml: &str) -> Policy {
+        Policy::construct(serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    fn resource(yaml: &str) -> Resource<serde_json::Value> {
+        Resource::from_yaml(yaml).unwrap().convert_to_json()
+    }
+
+    #[test]
+    fn finds_registries() {
+        assert_eq!("docker.io", registry("nginx"));
+        assert_eq!("docker.io", registry("library/nginx:1.25"));
+        assert_eq!("quay.io", registry("quay.io/prometheus/node-exporter"));
+        assert_eq!("registry.internal:5000", registry("registry.internal:5000/app@sha256:abc"));
+        assert_eq!("localhost", registry("localhost/app"));
+    }
+
+    #[test]
+    fn required_labels() {
+        let policy = policy("{ name: labels, requiredLabels: [ team, app ] }");
+        assert_eq!(vec!["labels: missing required label team"],
+            policy.violations(&resource("{ apiVersion: v1, kind: Pod, metadata: { labels: { app: web } } }")));
+        assert!(policy.violations(&resource("{ apiVersion: v1, kind: Pod, metadata: { labels: { app: web, team: a } } }")).is_empty());
+    }
+
+    #[test]
+    fn container_rules_cover_workloads() {
+        let policy = policy(r#"
+        name: images
+        gvk: { kinds: [ Pod, Deployment, CronJob ] }
+        forbiddenRegistries: [ docker.io, "*.untrusted.com" ]
+        requiredResourceLimits: [ memory ]
+        "#);
+        let deployment = resource(r#"
+        apiVersion: apps/v1
+        kind: Deployment
+        spec:
+          template:
+            spec:
+              initContainers:
+              - name: init
+                image: nginx
+                resources: { limits: { memory: 64Mi } }
+              containers:
+              - name: app
+                image: registry.internal/app:1
+              - name: proxy
+                image: eu.untrusted.com/proxy
+                resources: { limits: { memory: 64Mi } }
+        "#);
+        assert_eq!(vec![
+            "images: container app has no memory limit",
+            "images: container proxy uses image eu.untrusted.com/proxy from forbidden registry eu.untrusted.com",
+            "images: container init uses image nginx from forbidden registry docker.io",
+        ], policy.violations(&deployment));
+        let service = resource("{ apiVersion: v1, kind: Service, spec: { containers: [ { name: a, image: nginx } ] } }");
+        assert!(policy.violations(&service).is_empty(), "policy doesn't apply to services");
+    }
+
+    #[test]
+    fn ephemeral_containers_only_checked_for_registries() {
+        let policy = policy("{ name: images, forbiddenRegistries: [ docker.io ], requiredResourceLimits: [ memory ] }");
+        let pod = |image: &str| resource(&format!(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          containers:
+          - name: app
+            image: registry.internal/app:1
+            resources: {{ limits: {{ memory: 64Mi }} }}
+          ephemeralContainers:
+          - name: debugger
+            image: {}
+        "#, image));
+        assert!(policy.violations(&pod("registry.internal/busybox")).is_empty());
+        assert_eq!(vec!["images: container debugger uses image busybox from forbidden registry docker.io"], policy.violations(&pod("busybox")));
+    }
+
+    #[test]
+    fn restricted_to_namespaces() {
+        let policy = policy("{ name: labels, namespaces: [ '!kube-system' ], requiredLabels: [ team ] }");
+        assert!(policy.violations(&resource("{ apiVersion: v1, kind: Pod, metadata: { namespace: kube-system } }")).is_empty());
+        assert_eq!(1, policy.violations(&resource("{ apiVersion: v1, kind: Pod, metadata: { namespace: default } }")).len());
+    }
+
+}
@@ -0,0 +1,133 @@
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use serde_json_path::JsonPath;
+
+/// A predicate as written in a template's `when:` clause, e.g.
+/// `{ path: "$.spec.containers[*].image", startsWith: registry.internal/ }`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PredicateConfig {
+    path: String,
+    exists: Option<bool>,
+    equals: Option<Value>,
+    #[serde(rename = "in")]
+    in_values: Option<Vec<Value>>,
+    starts_with: Option<String>,
+    ends_with: Option<String>,
+    matches: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Test {
+    Exists(bool),
+    Equals(Value),
+    In(Vec<Value>),
+    StartsWith(String),
+    EndsWith(String),
+    Matches(Regex),
+}
+
+impl Test {
+    /// Exists checks whether anything was selected, the rest need at least one value to pass
+    fn passes(&self, values: Vec<&Value>) -> bool {
+        let any = |test: &dyn Fn(&Value) -> bool| values.iter().any(|value| test(value));
+        match self {
+            Test::Exists(exists) => values.is_empty() != *exists,
+            Test::Equals(expected) => any(&|value| value == expected),
+            Test::In(expected) => any(&|value| expected.contains(value)),
+            Test::StartsWith(prefix) => any(&|value| value.as_str().map(|s| s.starts_with(prefix.as_str())).unwrap_or(false)),
+            Test::EndsWith(suffix) => any(&|value| value.as_str().map(|s| s.ends_with(suffix.as_str())).unwrap_or(false)),
+            Test::Matches(regex) => any(&|value| value.as_str().map(|s| regex.is_match(s)).unwrap_or(false)),
+        }
+    }
+}
+
+/// A JSONPath query and a test that at least one of the values it selects must pass.
+/// With no test the path just has to select something.
+#[derive(Debug, Clone)]
+pub struct Predicate {
+    path: JsonPath,
+    test: Test,
+}
+
+impl Predicate {
+    pub fn compile(config: PredicateConfig) -> Result<Predicate, String> {
+        let path = JsonPath::parse(&config.path).map_err(|err| format!("Invalid JSONPath '{}': {}", config.path, err))?;
+        let mut tests = Vec::new();
+        tests.extend(config.exists.map(Test::Exists));
+        tests.extend(config.equals.map(Test::Equals));
+        tests.extend(config.in_values.map(Test::In));
+        tests.extend(config.starts_with.map(Test::StartsWith));
+        tests.extend(config.ends_with.map(Test::EndsWith));
+        if let Some(pattern) = config.matches {
+            tests.push(Test::Matches(Regex::new(&pattern).map_err(|err| format!("Invalid regex '{}': {}", pattern, err))?));
+        }
+        let test = match tests.len() {
+            0 => Test::Exists(true),
+            1 => tests.remove(0),
+            _ => return Err(format!("Only one test can be given for '{}'", config.path)),
+        };
+        Ok(Predicate { path, test })
+    }
+
+    pub fn matches(&self, object: &Value) -> bool {
+        self.test.passes(self.path.query(object).all())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Predicate;
+
+    fn predicate(yaml: &str) -> Predicate {
+        Predicate::compile(serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    fn pod() -> serde_json::Value {
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "spec": {
+                "containers": [
+                    { "name": "app", "image": "docker.io/app:1", "ports": [ { "containerPort": 8080 } ] },
+                    { "name": "proxy", "image": "registry.internal/proxy:2" },
+                ]
+            }
+        })
+    }
+
+    #[test]
+    fn any_selected_value_passes() {
+        assert!(predicate("{ path: '$.spec.containers[*].image', startsWith: registry.internal/ }").matches(&pod()));
+        assert!(!predicate("{ path: '$.spec.containers[*].image', startsWith: quay.io/ }").matches(&pod()));
+        assert!(predicate("{ path: '$.spec.containers[*].image', endsWith: ':1' }").matches(&pod()));
+        assert!(predicate("{ path: '$.spec.containers[*].image', matches: '^docker\\.io/.*:\\d+$' }").matches(&pod()));
+    }
+
+    #[test]
+    fn equals_and_in() {
+        assert!(predicate("{ path: '$.kind', equals: Pod }").matches(&pod()));
+        assert!(predicate("{ path: '$..containerPort', equals: 8080 }").matches(&pod()));
+        assert!(!predicate("{ path: '$..containerPort', equals: '8080' }").matches(&pod()));
+        assert!(predicate("{ path: '$.spec.containers[0].name', in: [ web, app ] }").matches(&pod()));
+        assert!(!predicate("{ path: '$.spec.type', in: [ LoadBalancer ] }").matches(&pod()));
+    }
+
+    #[test]
+    fn exists() {
+        assert!(predicate("{ path: '$.spec.containers[?@.name == \"proxy\"]' }").matches(&pod()));
+        assert!(!predicate("{ path: '$.spec.hostNetwork' }").matches(&pod()));
+        assert!(predicate("{ path: '$.spec.hostNetwork', exists: false }").matches(&pod()));
+    }
+
+    #[test]
+    fn rejects_invalid_predicates() {
+        let error = |yaml: &str| Predicate::compile(serde_yaml::from_str(yaml).unwrap()).err().unwrap();
+        assert!(error("{ path: 'spec' }").contains("JSONPath"));
+        assert!(error("{ path: '$.a', matches: '(' }").contains("regex"));
+        assert!(error("{ path: '$.a', equals: 1, startsWith: b }").contains("one test"));
+        assert!(serde_yaml::from_str::<super::PredicateConfig>("{ path: '$.a', contains: b }").is_err());
+    }
+
+}
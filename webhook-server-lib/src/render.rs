@@ -0,0 +1,231 @@
+use std::sync::Arc;
+
+use minijinja::{Environment, ErrorKind, UndefinedBehavior};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+const BODY: &str = "body";
+
+/// Values are rendered as markers holding their JSON in hex, which YAML reads as part of any scalar,
+/// and are only put back once the body has been parsed, so can't change its structure
+const VALUE_START: char = '\u{E000}';
+/// Marks a value written as the whole of a quoted scalar, which is put back as a string
+const STRING_START: char = '\u{E001}';
+const VALUE_END: char = '\u{E002}';
+
+static QUOTED_VALUE: Lazy<Regex> = Lazy::new(|| Regex::new("([\"'])\u{E000}([0-9a-f]*)\u{E002}([\"'])").unwrap());
+
+/// A template body written as a minijinja text template, rendering YAML.
+/// Missing values can be chained through, e.g. `{{ object.metadata.labels.app | default("unknown") }}`.
+/// A value making up the whole of an unquoted scalar keeps its type, e.g. `replicas: {{ object.spec.replicas }}`,
+/// and values within a scalar are written as text, e.g. `image: "registry.internal/{{ object.metadata.name }}:1"`.
+/// Either way values can't inject anything into the body. Safe strings, e.g. from `tojson`, are written as they are.
+#[derive(Clone)]
+pub struct BodyTemplate {
+    environment: Arc<Environment<'static>>,
+}
+
+impl BodyTemplate {
+    /// Syntax errors are found here, when the templates are loaded
+    pub fn compile(source: &str) -> Result<BodyTemplate, String> {
+        let mut environment = Environment::new();
+        environment.set_undefined_behavior(UndefinedBehavior::Chainable);
+        environment.set_formatter(|out, state, value| {
+            if value.is_undefined() || value.is_safe() {
+                return minijinja::escape_formatter(out, state, value);
+            }
+            let json = serde_json::to_string(value)
+                .map_err(|err| minijinja::Error::new(ErrorKind::BadSerialization, "value can't be written as JSON").with_source(err))?;
+            let hex: String = json.bytes().map(|byte| format!("{:02x}", byte)).collect();
+            write!(out, "{}{}{}", VALUE_START, hex, VALUE_END)?;
+            Ok(())
+        });
+        environment.add_template_owned(BODY, source.to_string()).map_err(|err| describe(&err))?;
+        Ok(BodyTemplate { environment: Arc::new(environment) })
+    }
+
+    /// Renders the body and parses it as a YAML mapping
+    pub fn render(&self, context: &serde_json::Value) -> Result<serde_yaml::Mapping, String> {
+        let rendered = self.environment.get_template(BODY)
+            .and_then(|template| template.render(context))
+            .map_err(|err| describe(&err))?;
+        let rendered = QUOTED_VALUE.replace_all(&rendered, |captures: &regex::Captures| match captures[1] == captures[3] {
+            true => format!("{}{}{}{}{}", &captures[1], STRING_START, &captures[2], VALUE_END, &captures[3]),
+            false => captures[0].to_string(),
+        });
+        match serde_yaml::from_str::<Option<serde_yaml::Mapping>>(&rendered) {
+            Ok(mapping) => restore_mapping(mapping.unwrap_or_default()),
+            Err(err) => Err(format!("Rendered body isn't a YAML mapping: {}\n{}", err, restore_text(&rendered)?)),
+        }
+    }
+}
+
+fn restore_mapping(mapping: serde_yaml::Mapping) -> Result<serde_yaml::Mapping, String> {
+    mapping.into_iter()
+        .map(|(key, value)| Ok((restore(key)?, restore(value)?)))
+        .collect()
+}
+
+/// Puts the rendered values back into the parsed body
+fn restore(value: serde_yaml::Value) -> Result<serde_yaml::Value, String> {
+    match value {
+        serde_yaml::Value::String(text) => match whole_value(&text) {
+            Some(hex) => serde_yaml::to_value(decode(hex)?).map_err(|err| err.to_string()),
+            None => restore_text(&text).map(serde_yaml::Value::String),
+        },
+        serde_yaml::Value::Sequence(items) => items.into_iter().map(restore).collect::<Result<_, _>>().map(serde_yaml::Value::Sequence),
+        serde_yaml::Value::Mapping(mapping) => restore_mapping(mapping).map(serde_yaml::Value::Mapping),
+        serde_yaml::Value::Tagged(mut tagged) => {
+            tagged.value = restore(tagged.value)?;
+            Ok(serde_yaml::Value::Tagged(tagged))
+        },
+        other => Ok(other),
+    }
+}
+
+/// The hex of the value making up the whole of an unquoted scalar
+fn whole_value(text: &str) -> Option<&str> {
+    text.strip_prefix(VALUE_START)
+        .and_then(|rest| rest.strip_suffix(VALUE_END))
+        .filter(|hex| !hex.contains(VALUE_END))
+}
+
+/// Replaces each value in the text with it written as text, strings without quotes and null as nothing
+fn restore_text(text: &str) -> Result<String, String> {
+    let mut restored = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find([VALUE_START, STRING_START]) {
+        restored.push_str(&rest[..start]);
+        let marked = &rest[start + VALUE_START.len_utf8()..];
+        let (hex, after) = marked.split_once(VALUE_END).ok_or("Unterminated value in rendered body")?;
+        match decode(hex)? {
+            serde_json::Value::String(string) => restored.push_str(&string),
+            serde_json::Value::Null => (),
+            other => restored.push_str(&other.to_string()),
+        }
+        rest = after;
+    }
+    restored.push_str(rest);
+    Ok(restored)
+}
+
+fn decode(hex: &str) -> Result<serde_json::Value, String> {
+    let bytes = (0..hex.len()).step_by(2)
+        .map(|index| hex.get(index..index + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| format!("Malformed value {} in rendered body", hex))?;
+    serde_json::from_slice(&bytes).map_err(|err| format!("Malformed value in rendered body: {}", err))
+}
+
+/// Includes the line and any underlying cause, which the error's own message leaves out
+fn describe(err: &minijinja::Error) -> String {
+    let mut description = match err.line() {
+        Some(line) => format!("{} (line {})", err, line),
+        None => err.to_string(),
+    };
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        description.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::BodyTemplate;
+
+    #[test]
+    fn renders_with_loops_conditionals_and_defaults() {
+        let body = BodyTemplate::compile(r#"
+spec:
+  containers:
+{% for container in object.spec.containers %}
+  - name: {{ container.name }}
+    env:
+    - name: SERVICE_NAME
+      value: {{ object.metadata.labels.app | default("unknown") | tojson }}
+{% if request.operation == "CREATE" %}
+    - name: CREATED
+      value: "true"
+{% endif %}
+{% endfor %}
+"#).unwrap();
+        let context = json!({
+            "object": { "metadata": {}, "spec": { "containers": [ { "name": "a" }, { "name": "b" } ] } },
+            "request": { "operation": "CREATE" },
+        });
+        let expected: serde_yaml::Mapping = serde_yaml::from_str(r#"
+        spec:
+          containers:
+          - name: a
+            env:
+            - { name: SERVICE_NAME, value: unknown }
+            - { name: CREATED, value: "true" }
+          - name: b
+            env:
+            - { name: SERVICE_NAME, value: unknown }
+            - { name: CREATED, value: "true" }
+        "#).unwrap();
+        assert_eq!(expected, body.render(&context).unwrap());
+    }
+
+    #[test]
+    fn escapes_values() {
+        let body = BodyTemplate::compile("metadata:\n  labels:\n    app: {{ object.name }}\n    replicas: {{ object.replicas }}").unwrap();
+        let context = json!({ "object": { "name": "a\n  hostNetwork: true", "replicas": 2 } });
+        let expected: serde_yaml::Mapping = serde_yaml::from_str(r#"
+        metadata:
+          labels:
+            app: "a\n  hostNetwork: true"
+            replicas: 2
+        "#).unwrap();
+        assert_eq!(expected, body.render(&context).unwrap());
+    }
+
+    #[test]
+    fn empty_render_is_an_empty_mapping() {
+        let body = BodyTemplate::compile("{% if false %}spec: {}{% endif %}").unwrap();
+        assert!(body.render(&json!({})).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_syntax_errors_at_compile() {
+        let err = BodyTemplate::compile("spec:\n  a: {% for x in %}").err().unwrap();
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn reports_render_errors() {
+        let body = BodyTemplate::compile("spec: {{ object.count + 'a' }}").unwrap();
+        assert!(body.render(&json!({ "object": { "count": 1 } })).is_err());
+        let not_yaml = BodyTemplate::compile("- {{ object.name }}").unwrap();
+        let err = not_yaml.render(&json!({ "object": { "name": "a" } })).err().unwrap();
+        assert!(err.contains("mapping") && err.contains("- a"), "{}", err);
+    }
+
+    #[test]
+    fn interpolates_values_within_scalars() {
+        let body = BodyTemplate::compile(r#"
+spec:
+  replicas: {{ object.replicas }}
+  image: "registry.internal/{{ object.app }}:1"
+  command: echo {{ object.app }} x{{ object.replicas }}
+  label: '{{ object.replicas }}'
+  attributes: "service.name={{ object.app }},{{ object.missing }}team={{ object.team }}"
+"#).unwrap();
+        let context = json!({ "object": { "app": "web", "replicas": 2, "team": "a\", injected: \"b" } });
+        let expected: serde_yaml::Mapping = serde_yaml::from_str(r#"
+        spec:
+          replicas: 2
+          image: registry.internal/web:1
+          command: echo web x2
+          label: "2"
+          attributes: "service.name=web,team=a\", injected: \"b"
+        "#).unwrap();
+        assert_eq!(expected, body.render(&context).unwrap());
+    }
+
+}
@@ -5,26 +5,135 @@ use std::collections::BTreeMap;
 
 type MapType = BTreeMap<String, String>;
 
+/// List fields which are merged by key, mapped to the fields identifying their items.
+/// Where more than one field is given the first one present on an item is used.
+/// Fields joined with `+`, e.g. `key+operator+effect`, identify an item by all of them together.
+/// Lists are looked up by their field name alone, so the keys apply to a list of that name at any depth.
+pub type MergeKeys = BTreeMap<String, Vec<String>>;
+
+/// The Kubernetes strategic merge keys for the commonly templated lists
+pub fn default_merge_keys() -> MergeKeys {
+    [
+        ("containers", vec!["name"]),
+        ("initContainers", vec!["name"]),
+        ("ephemeralContainers", vec!["name"]),
+        ("volumes", vec!["name"]),
+        ("env", vec!["name"]),
+        ("ports", vec!["containerPort", "port"]),
+        ("volumeMounts", vec!["mountPath"]),
+        ("volumeDevices", vec!["devicePath"]),
+        ("tolerations", vec!["key+operator+effect"]),
+        ("imagePullSecrets", vec!["name"]),
+        ("hostAliases", vec!["ip"]),
+    ].into_iter()
+        .map(|(field, keys)| (field.to_string(), keys.into_iter().map(String::from).collect()))
+        .collect()
+}
+
+/// How the second (template) value is combined with the first (object) value at a path
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategy {
+    /// Objects are merged, lists are merged by key or concatenated and the first's scalars win
+    #[default]
+    Merge,
+    /// The second value replaces the first
+    Replace,
+    /// The second value is only used if the first is absent
+    SkipIfPresent,
+    /// The second's list items are placed after the first's. For lists with merge keys, any of the
+    /// first's items with the same key as one of the second's are merged into it rather than kept in place.
+    Append,
+    /// The second's list items are placed before the first's, merging items by key as for `Append`
+    Prepend,
+}
+
+/// How values are combined by `merge_with`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MergeOptions {
+    pub merge_keys: MergeKeys,
+    /// Keyed by the dotted path from the top of the resource, e.g. `spec.initContainers`.
+    /// List items share the path of their list, so `spec.containers.image` applies to every container.
+    /// Metadata has its own merge, so isn't affected.
+    pub strategies: BTreeMap<String, MergeStrategy>,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions { merge_keys: default_merge_keys(), strategies: BTreeMap::new() }
+    }
+}
+
+impl MergeOptions {
+
+    fn strategy(&self, path: &[&str]) -> MergeStrategy {
+        if self.strategies.is_empty() {
+            return MergeStrategy::Merge;
+        }
+        self.strategies.get(&path.join(".")).copied().unwrap_or_default()
+    }
+
+    fn merge_keys(&self, path: &[&str]) -> Option<&Vec<String>> {
+        path.last().and_then(|field| self.merge_keys.get(*field))
+    }
+
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
+pub struct OwnerReference {
+    pub api_version: String,
+    pub kind: String,
+    pub name: String,
+    pub uid: String,
+    pub controller: Option<bool>,
+    pub block_owner_deletion: Option<bool>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct ObjectMeta {
     name: Option<String>,
+    pub generate_name: Option<String>,
     pub namespace: Option<String>,
+    self_link: Option<String>,
+    pub uid: Option<String>,
+    resource_version: Option<String>,
+    generation: Option<i64>,
+    creation_timestamp: Option<String>,
+    deletion_timestamp: Option<String>,
+    deletion_grace_period_seconds: Option<i64>,
     pub labels: Option<MapType>,
     pub annotations: Option<MapType>,
+    pub owner_references: Option<Vec<OwnerReference>>,
+    pub finalizers: Option<Vec<String>>,
+    managed_fields: Option<Vec<serde_json::Value>>,
+    /// Any fields added to ObjectMeta in later Kubernetes versions
+    #[serde(flatten)]
+    other: BTreeMap<String, serde_json::Value>,
 }
 
-fn write_string_thing(f: &mut fmt::Formatter<'_>, opt: &Option<String>) -> fmt::Result {
-    opt.as_ref().map(|n| write!(f, "{},", n)).unwrap_or_else(|| write!(f, ""))
+impl ObjectMeta {
+    /// The namespace and the name, or generateName, of those present
+    fn identity(&self) -> Vec<String> {
+        let name = match (self.name.as_ref(), self.generate_name.as_ref()) {
+            (Some(name), _) => Some(format!("name:{}", name)),
+            (None, Some(generate_name)) => Some(format!("generateName:{}", generate_name)),
+            (None, None) => None,
+        };
+        self.namespace.as_ref().map(|namespace| format!("namespace:{}", namespace)).into_iter()
+            .chain(name)
+            .collect()
+    }
 }
 
+/// Only the identifying fields, as annotations in particular may hold secrets or large configuration
 impl fmt::Display for ObjectMeta {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "M(")
-            .and_then(|_| write_string_thing(f, &self.name))
-            .and_then(|_| write_string_thing(f, &self.namespace))
-            .and_then(|_| write!(f, "{:?},{:?})", self.labels, self.annotations))
+        let uid = self.uid.as_ref().map(|uid| format!("uid:{}", uid));
+        write!(f, "M({})", self.identity().into_iter().chain(uid).collect::<Vec<_>>().join(","))
     }
 }
 
@@ -36,11 +145,21 @@ pub struct Resource<T> {
     pub kind: String,
     pub metadata: Option<ObjectMeta>,
     spec: Option<T>,
+    /// All other top level fields, e.g. `data`, `rules` or `status`
+    #[serde(flatten)]
+    other: BTreeMap<String, T>,
 }
 
-impl <T: fmt::Debug> fmt::Display for Resource<T> {
+/// Only the identifying fields, as resources are logged and may hold e.g. a Secret's `data`
+impl <T> fmt::Display for Resource<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "R(apVersion:{},kind:{},metadata:{:?},spec:{:?})", self.api_version, self.kind, self.metadata, self.spec)
+        write!(f, "R(apiVersion:{},kind:{}", self.api_version, self.kind)?;
+        if let Some(meta) = self.metadata.as_ref() {
+            for part in meta.identity() {
+                write!(f, ",{}", part)?;
+            }
+        }
+        write!(f, ")")
     }
 }
 
@@ -52,15 +171,18 @@ impl <T: Clone> Resource<T> {
             kind: kind.to_string(),
             metadata: None,
             spec: None,
+            other: BTreeMap::new(),
         }
     }
 
-    fn internal_merge(&self, other: &Resource<T>, merge_values: fn (&T, &T) -> T) -> Resource<T> {
+    /// `merge_values` is given the name of the field holding the values being merged
+    fn internal_merge(&self, other: &Resource<T>, merge_values: &dyn Fn (&str, &T, &T) -> T) -> Resource<T> {
         Resource {
             api_version: self.api_version.clone(),
             kind: self.kind.clone(),
             metadata: merge_meta(&self.metadata, &other.metadata),
-            spec: merge_opt_values(&self.spec, &other.spec, merge_values),
+            spec: merge_opt_values(&self.spec, &other.spec, &|v1, v2| merge_values("spec", v1, v2)),
+            other: merge_others(&self.other, &other.other, merge_values),
         }
     }
 
@@ -77,23 +199,46 @@ impl Resource<serde_json::Value> {
     }
 
     pub fn merge(&self, other: &Resource<serde_json::Value>) -> Resource<serde_json::Value> {
-        self.internal_merge(other, Self::merge_values)
+        self.merge_with(other, &MergeOptions::default())
     }
 
-    fn merge_values(first: &serde_json::Value, second: &serde_json::Value) -> serde_json::Value {
+    pub fn merge_with(&self, other: &Resource<serde_json::Value>, options: &MergeOptions) -> Resource<serde_json::Value> {
+        self.internal_merge(other, &|field, v1, v2| Self::merge_values(&[field], v1, v2, options))
+    }
+
+    fn merge_values(path: &[&str], first: &serde_json::Value, second: &serde_json::Value, options: &MergeOptions) -> serde_json::Value {
+        match (options.strategy(path), first, second) {
+            (MergeStrategy::Replace, _, _) =>
+                return second.clone(),
+            (MergeStrategy::SkipIfPresent, &serde_json::Value::Null, _) =>
+                return second.clone(),
+            (MergeStrategy::SkipIfPresent, _, _) =>
+                return first.clone(),
+            (strategy @ (MergeStrategy::Append | MergeStrategy::Prepend), serde_json::Value::Array(vec1), serde_json::Value::Array(vec2)) =>
+                return match options.merge_keys(path) {
+                    Some(keys) => place_keyed_arrays(vec1, vec2, keys, strategy == MergeStrategy::Prepend, |item, key| item.get(key),
+                        &|v1, v2| Self::merge_values(path, v1, v2, options), Self::construct_array_wrapper),
+                    None if strategy == MergeStrategy::Prepend => merge_arrays(vec2, vec1, Self::construct_array_wrapper),
+                    None => merge_arrays(vec1, vec2, Self::construct_array_wrapper),
+                },
+            _ => (),
+        }
         match (first, second) {
             (serde_json::Value::Bool(b1), serde_json::Value::Bool(_)) =>
-                serde_json::Value::Bool(b1.clone()),
+                serde_json::Value::Bool(*b1),
             (serde_json::Value::Number(n1), serde_json::Value::Number(_)) =>
                 serde_json::Value::Number(n1.clone()),
             (serde_json::Value::String(s1), serde_json::Value::String(_)) =>
                 serde_json::Value::String(s1.clone()),
-            (serde_json::Value::Array(vec1), serde_json::Value::Array(vec2)) =>
-                merge_arrays(vec1, vec2, Self::construct_array_wrapper),
+            (serde_json::Value::Array(vec1), serde_json::Value::Array(vec2)) => match options.merge_keys(path) {
+                Some(keys) => merge_keyed_arrays(vec1, vec2, keys, |item, key| item.get(key),
+                    &|v1, v2| Self::merge_values(path, v1, v2, options), Self::construct_array_wrapper),
+                None => merge_arrays(vec1, vec2, Self::construct_array_wrapper),
+            },
             (serde_json::Value::Array(vec), _) =>
-                merge_value_into_array(vec, second, Self::merge_values, Self::construct_array_wrapper),
+                merge_value_into_array(vec, second, &|v1, v2| Self::merge_values(path, v1, v2, options), Self::construct_array_wrapper),
             (serde_json::Value::Object(map1), serde_json::Value::Object(map2)) =>
-                Self::merge_object_maps(map1, map2),
+                Self::merge_object_maps(path, map1, map2, options),
             (&serde_json::Value::Null, _) =>
                 second.clone(),
             (_, &serde_json::Value::Null) =>
@@ -109,11 +254,11 @@ impl Resource<serde_json::Value> {
         serde_json::Value::Array(array)
     }
 
-    fn merge_object_maps(first: &serde_json::Map<String, serde_json::Value>, second: &serde_json::Map<String, serde_json::Value>) -> serde_json::Value {
+    fn merge_object_maps(path: &[&str], first: &serde_json::Map<String, serde_json::Value>, second: &serde_json::Map<String, serde_json::Value>, options: &MergeOptions) -> serde_json::Value {
         let mut new_map = serde_json::Map::with_capacity(first.len() + second.len());
         //Add all in the first
         for (key, v1) in first.iter() {
-            let new_value = second.get(key).map(|v2| Self::merge_values(v1, v2)).unwrap_or(v1.clone());
+            let new_value = second.get(key).map(|v2| Self::merge_values(&[path, &[key.as_str()]].concat(), v1, v2, options)).unwrap_or(v1.clone());
             new_map.insert(key.clone(), new_value);
         }
         //Add any in the second not already added
@@ -138,21 +283,44 @@ impl Resource<serde_yaml::Value> {
     }
 
     pub fn merge(&self, other: &Resource<serde_yaml::Value>) -> Resource<serde_yaml::Value> {
-        self.internal_merge(other, Self::merge_values)
+        self.merge_with(other, &MergeOptions::default())
+    }
+
+    pub fn merge_with(&self, other: &Resource<serde_yaml::Value>, options: &MergeOptions) -> Resource<serde_yaml::Value> {
+        self.internal_merge(other, &|field, v1, v2| Self::merge_values(&[field], v1, v2, options))
     }
 
-    fn merge_values(first: &serde_yaml::Value, second: &serde_yaml::Value) -> serde_yaml::Value {
+    fn merge_values(path: &[&str], first: &serde_yaml::Value, second: &serde_yaml::Value, options: &MergeOptions) -> serde_yaml::Value {
+        match (options.strategy(path), first, second) {
+            (MergeStrategy::Replace, _, _) =>
+                return second.clone(),
+            (MergeStrategy::SkipIfPresent, &serde_yaml::Value::Null, _) =>
+                return second.clone(),
+            (MergeStrategy::SkipIfPresent, _, _) =>
+                return first.clone(),
+            (strategy @ (MergeStrategy::Append | MergeStrategy::Prepend), serde_yaml::Value::Sequence(seq1), serde_yaml::Value::Sequence(seq2)) =>
+                return match options.merge_keys(path) {
+                    Some(keys) => place_keyed_arrays(seq1, seq2, keys, strategy == MergeStrategy::Prepend, |item, key| item.get(key),
+                        &|v1, v2| Self::merge_values(path, v1, v2, options), Self::construct_array_wrapper),
+                    None if strategy == MergeStrategy::Prepend => merge_arrays(seq2, seq1, Self::construct_array_wrapper),
+                    None => merge_arrays(seq1, seq2, Self::construct_array_wrapper),
+                },
+            _ => (),
+        }
         match (first, second) {
             (serde_yaml::Value::Bool(b1), serde_yaml::Value::Bool(_)) =>
-                serde_yaml::Value::Bool(b1.clone()),
+                serde_yaml::Value::Bool(*b1),
             (serde_yaml::Value::Number(n1), serde_yaml::Value::Number(_)) =>
                 serde_yaml::Value::Number(n1.clone()),
             (serde_yaml::Value::String(s1), serde_yaml::Value::String(_)) =>
                 serde_yaml::Value::String(s1.clone()),
-            (serde_yaml::Value::Sequence(seq1), serde_yaml::Value::Sequence(seq2)) =>
-                merge_arrays(seq1, seq2, Self::construct_array_wrapper),
+            (serde_yaml::Value::Sequence(seq1), serde_yaml::Value::Sequence(seq2)) => match options.merge_keys(path) {
+                Some(keys) => merge_keyed_arrays(seq1, seq2, keys, |item, key| item.get(key),
+                    &|v1, v2| Self::merge_values(path, v1, v2, options), Self::construct_array_wrapper),
+                None => merge_arrays(seq1, seq2, Self::construct_array_wrapper),
+            },
             (serde_yaml::Value::Mapping(map1), serde_yaml::Value::Mapping(map2)) =>
-                Self::merge_mappings(map1, map2),
+                Self::merge_mappings(path, map1, map2, options),
             (serde_yaml::Value::Tagged(box1), serde_yaml::Value::Tagged(_)) =>
                 serde_yaml::Value::Tagged(box1.clone()),
             (&serde_yaml::Value::Null, _) => second.clone(),
@@ -168,11 +336,12 @@ impl Resource<serde_yaml::Value> {
         serde_yaml::Value::Sequence(array)
     }
 
-    fn merge_mappings(first: &serde_yaml::Mapping, second: &serde_yaml::Mapping) -> serde_yaml::Value {
+    fn merge_mappings(path: &[&str], first: &serde_yaml::Mapping, second: &serde_yaml::Mapping, options: &MergeOptions) -> serde_yaml::Value {
         let mut new_map = serde_yaml::Mapping::with_capacity(first.len() + second.len());
         //Add all in the first
         for (key, v1) in first.iter() {
-            let new_value = second.get(key).map(|v2| Self::merge_values(v1, v2)).unwrap_or(v1.clone());
+            let child_path = [path, &[key.as_str().unwrap_or_default()]].concat();
+            let new_value = second.get(key).map(|v2| Self::merge_values(&child_path, v1, v2, options)).unwrap_or(v1.clone());
             new_map.insert(key.clone(), new_value);
         }
         //Add any in the second not already added
@@ -189,29 +358,31 @@ impl Resource<serde_yaml::Value> {
             api_version: self.api_version.clone(),
             kind: self.kind.clone(),
             metadata: self.metadata.clone(),
-            spec: self.spec.as_ref().map(Self::convert_value_to_json).flatten()
+            spec: self.spec.as_ref().and_then(Self::convert_value_to_json),
+            other: self.other.iter()
+                .filter_map(|(key, value)| Self::convert_value_to_json(value).map(|v| (key.clone(), v)))
+                .collect(),
         }
     }
 
     fn convert_value_to_json(yaml: &serde_yaml::Value) -> Option<serde_json::Value> {
         match yaml {
             serde_yaml::Value::Bool(b) =>
-                Some(serde_json::Value::Bool(b.clone())),
+                Some(serde_json::Value::Bool(*b)),
             serde_yaml::Value::Number(n) =>
-                Self::convert_number_to_json(n).map(|n| serde_json::Value::Number(n)),
+                Self::convert_number_to_json(n).map(serde_json::Value::Number),
             serde_yaml::Value::String(s) =>
                 Some(serde_json::Value::String(s.clone())),
             serde_yaml::Value::Sequence(seq) => {
                 let items: Vec<serde_json::Value> = seq.iter()
-                    .filter_map(|v| Self::convert_value_to_json(v))
+                    .filter_map(Self::convert_value_to_json)
                     .collect();
                 Some(serde_json::Value::Array(items))
             },
             serde_yaml::Value::Mapping(map) => {
                 let new_map = map.iter().fold(serde_json::Map::new(), |mut acc, (key, value)| {
                     if let Some((k, v)) = Self::convert_value_to_string(key)
-                        .map(|k| Self::convert_value_to_json(value).map(|v| (k, v)))
-                        .flatten() {
+                        .and_then(|k| Self::convert_value_to_json(value).map(|v| (k, v))) {
                             acc.insert(k, v);
                         }
                     acc
@@ -229,9 +400,9 @@ impl Resource<serde_yaml::Value> {
     }
 
     fn convert_number_to_json(yaml: &serde_yaml::Number) -> Option<serde_json::Number> {
-        None.or_else(|| yaml.as_u64().map(|u| serde_json::Number::from(u)))
-            .or_else(|| yaml.as_i64().map(|i| serde_json::Number::from(i)))
-            .or_else(|| yaml.as_f64().map(|f| serde_json::Number::from_f64(f)).flatten())
+        None.or_else(|| yaml.as_u64().map(serde_json::Number::from))
+            .or_else(|| yaml.as_i64().map(serde_json::Number::from))
+            .or_else(|| yaml.as_f64().and_then(serde_json::Number::from_f64))
     }
 
     fn convert_value_to_string(yaml: &serde_yaml::Value) -> Option<String> {
@@ -248,19 +419,53 @@ impl Resource<serde_yaml::Value> {
 
 }
 
+/// Identity and server managed fields always come from the first
 fn merge_meta(first: &Option<ObjectMeta>, second: &Option<ObjectMeta>) -> Option<ObjectMeta> {
     first.as_ref().map(|f| {
         second.as_ref().map(|s| {
             ObjectMeta {
-                name: f.name.clone(),
-                namespace: f.namespace.clone(),
                 labels: merge_maps(&f.labels, &s.labels),
                 annotations: merge_maps(&f.annotations, &s.annotations),
+                owner_references: merge_owner_references(&f.owner_references, &s.owner_references),
+                finalizers: merge_finalizers(&f.finalizers, &s.finalizers),
+                other: s.other.iter().fold(f.other.clone(), |mut acc, (k, v)| {
+                    acc.entry(k.clone()).or_insert_with(|| v.clone());
+                    acc
+                }),
+                ..f.clone()
             }
         }).unwrap_or(f.clone())
     }).or(second.clone())
 }
 
+/// Set union, keeping the order of the first followed by any new from the second
+fn merge_finalizers(first: &Option<Vec<String>>, second: &Option<Vec<String>>) -> Option<Vec<String>> {
+    first.as_ref().map(|f| {
+        second.as_ref().map(|s| {
+            s.iter().fold(f.clone(), |mut acc, finalizer| {
+                if !acc.contains(finalizer) {
+                    acc.push(finalizer.clone());
+                }
+                acc
+            })
+        }).unwrap_or(f.clone())
+    }).or(second.clone())
+}
+
+/// Keyed by uid, the first's reference wins when both have the same uid
+fn merge_owner_references(first: &Option<Vec<OwnerReference>>, second: &Option<Vec<OwnerReference>>) -> Option<Vec<OwnerReference>> {
+    first.as_ref().map(|f| {
+        second.as_ref().map(|s| {
+            s.iter().fold(f.clone(), |mut acc, reference| {
+                if !acc.iter().any(|r| r.uid == reference.uid) {
+                    acc.push(reference.clone());
+                }
+                acc
+            })
+        }).unwrap_or(f.clone())
+    }).or(second.clone())
+}
+
 fn merge_maps(first: &Option<MapType>, second: &Option<MapType>) -> Option<MapType> {
     first.as_ref().map(|f| {
         second.as_ref().map(|s| {
@@ -272,7 +477,7 @@ fn merge_maps(first: &Option<MapType>, second: &Option<MapType>) -> Option<MapTy
     }).or(second.clone())
 }
 
-fn merge_opt_values<T: Clone>(first: &Option<T>, second: &Option<T>, merge_values: fn (&T, &T) -> T) -> Option<T> {
+fn merge_opt_values<T: Clone>(first: &Option<T>, second: &Option<T>, merge_values: &dyn Fn (&T, &T) -> T) -> Option<T> {
     first.as_ref().map(|v1| {
         second.as_ref().map(|v2| {
             merge_values(v1, v2)
@@ -280,7 +485,15 @@ fn merge_opt_values<T: Clone>(first: &Option<T>, second: &Option<T>, merge_value
     }).or(second.clone())
 }
 
-fn merge_arrays<T: Clone>(first: &Vec<T>, second: &Vec<T>, construct_array_wrapper: fn (Vec<T>) -> T) -> T {
+fn merge_others<T: Clone>(first: &BTreeMap<String, T>, second: &BTreeMap<String, T>, merge_values: &dyn Fn (&str, &T, &T) -> T) -> BTreeMap<String, T> {
+    second.iter().fold(first.clone(), |mut acc, (key, v2)| {
+        let new_value = acc.get(key).map(|v1| merge_values(key, v1, v2)).unwrap_or_else(|| v2.clone());
+        acc.insert(key.clone(), new_value);
+        acc
+    })
+}
+
+fn merge_arrays<T: Clone>(first: &[T], second: &[T], construct_array_wrapper: fn (Vec<T>) -> T) -> T {
     let first_copies = first.iter()
         .fold(Vec::with_capacity(first.len() + second.len()), |mut acc, v| {
             acc.push(v.clone());
@@ -294,8 +507,69 @@ fn merge_arrays<T: Clone>(first: &Vec<T>, second: &Vec<T>, construct_array_wrapp
     construct_array_wrapper(new_vec)
 }
 
-fn merge_value_into_array<T: Clone>(vec: &Vec<T>, value: &T,
-    merge_values: fn (&T, &T) -> T, construct_array_wrapper: fn (Vec<T>) -> T) -> T {
+/// The merge key an item matched on and the values of its fields, None for items without one
+type ItemKey<'k, T> = Option<(&'k str, Vec<Option<T>>)>;
+
+fn item_keys<'k, T: Clone>(items: &[T], keys: &'k [String], get_field: for<'a> fn (&'a T, &str) -> Option<&'a T>) -> Vec<ItemKey<'k, T>> {
+    items.iter()
+        .map(|item| keys.iter().find_map(|key| {
+            let values: Vec<Option<T>> = key.split('+').map(|field| get_field(item, field).cloned()).collect();
+            values.iter().any(Option::is_some).then_some((key.as_str(), values))
+        }))
+        .collect()
+}
+
+/// Strategic merge of lists: items with the same merge key are merged together, any in
+/// the second without a match (or without a merge key) are appended.
+fn merge_keyed_arrays<T: Clone + PartialEq>(first: &[T], second: &[T], keys: &[String],
+    get_field: for<'a> fn (&'a T, &str) -> Option<&'a T>, merge_values: &dyn Fn (&T, &T) -> T,
+    construct_array_wrapper: fn (Vec<T>) -> T) -> T {
+
+    let second_keys = item_keys(second, keys, get_field);
+    let first_keys = item_keys(first, keys, get_field);
+    let merged = first.iter().zip(first_keys.iter())
+        .map(|(item, item_key)| {
+            item_key.as_ref()
+                .and_then(|k| second_keys.iter().position(|other_key| other_key.as_ref() == Some(k)))
+                .map(|index| merge_values(item, &second[index]))
+                .unwrap_or_else(|| item.clone())
+        })
+        .collect::<Vec<T>>();
+    let new_vec = second.iter().zip(second_keys.iter())
+        .filter(|(_, item_key)| item_key.as_ref().map(|k| !first_keys.contains(&Some(k.clone()))).unwrap_or(true))
+        .fold(merged, |mut acc, (item, _)| {
+            acc.push(item.clone());
+            acc
+        });
+    construct_array_wrapper(new_vec)
+}
+
+/// Places the second's items before or after the first's. Any of the first's items with the same
+/// merge key as one of the second's is merged into it, so items already present aren't duplicated.
+fn place_keyed_arrays<T: Clone + PartialEq>(first: &[T], second: &[T], keys: &[String], before: bool,
+    get_field: for<'a> fn (&'a T, &str) -> Option<&'a T>, merge_values: &dyn Fn (&T, &T) -> T,
+    construct_array_wrapper: fn (Vec<T>) -> T) -> T {
+
+    let first_keys = item_keys(first, keys, get_field);
+    let second_keys = item_keys(second, keys, get_field);
+    let placed: Vec<T> = second.iter().zip(second_keys.iter())
+        .map(|(item, item_key)| {
+            item_key.as_ref()
+                .and_then(|k| first_keys.iter().position(|other_key| other_key.as_ref() == Some(k)))
+                .map(|index| merge_values(&first[index], item))
+                .unwrap_or_else(|| item.clone())
+        })
+        .collect();
+    let rest: Vec<T> = first.iter().zip(first_keys.iter())
+        .filter(|(_, item_key)| item_key.as_ref().map(|k| !second_keys.contains(&Some(k.clone()))).unwrap_or(true))
+        .map(|(item, _)| item.clone())
+        .collect();
+    let new_vec = if before { [placed, rest].concat() } else { [rest, placed].concat() };
+    construct_array_wrapper(new_vec)
+}
+
+fn merge_value_into_array<T: Clone>(vec: &[T], value: &T,
+    merge_values: &dyn Fn (&T, &T) -> T, construct_array_wrapper: fn (Vec<T>) -> T) -> T {
 
     let merged_vec: Vec<T> = vec.iter().map(|item| merge_values(item, value)).collect();
     construct_array_wrapper(merged_vec)
@@ -355,7 +629,7 @@ mod tests {
     #[test]
     fn merge_combines_both_no_labels_in_first_no_annotations_in_second_no_spec() {
         let first: Resource<serde_json::Value> = Resource{
-            api_version: V1.clone(), kind: POD.clone(), spec: None,
+            api_version: V1.clone(), kind: POD.clone(), spec: None, other: Default::default(),
             metadata: Some(super::ObjectMeta {
                 name: Some(String::from("my-pod")),
                 namespace: Some(String::from("my-namespace")),
@@ -364,10 +638,11 @@ mod tests {
                     (String::from("annot_1"), String::from("1_annot")),
                     (String::from("annot_1_other"), String::from("1_other_annot"))
                 ])),
+                ..Default::default()
             }),
         };
         let second = Resource {
-            api_version: V1.clone(), kind: POD.clone(), spec: None,
+            api_version: V1.clone(), kind: POD.clone(), spec: None, other: Default::default(),
             metadata: Some(super::ObjectMeta {
                 name: Some(String::from("my-pod")),
                 namespace: Some(String::from("my-namespace")),
@@ -376,6 +651,7 @@ mod tests {
                     (String::from("in_2_other"), String::from("other_in_2"))
                 ])),
                 annotations: None,
+                ..Default::default()
             }),
         };
         println!("First A: {}", first);
@@ -383,7 +659,7 @@ mod tests {
         let merged = first.merge(&second);
         println!("Merged A: {}", merged);
         let expected = Resource {
-            api_version: V1.clone(), kind: POD.clone(), spec: None,
+            api_version: V1.clone(), kind: POD.clone(), spec: None, other: Default::default(),
             metadata: Some(super::ObjectMeta {
                 name: Some(String::from("my-pod")),
                 namespace: Some(String::from("my-namespace")),
@@ -395,6 +671,7 @@ mod tests {
                     (String::from("annot_1"), String::from("1_annot")),
                     (String::from("annot_1_other"), String::from("1_other_annot"))
                 ])),
+                ..Default::default()
             }),
         };
         assert_eq!(expected, merged);
@@ -403,7 +680,7 @@ mod tests {
     #[test]
     fn merge_combines_both_meta_and_annotations_in_both_no_spec() {
         let first: Resource<serde_json::Value> = Resource {
-            api_version: V1.clone(), kind: POD.clone(), spec: None,
+            api_version: V1.clone(), kind: POD.clone(), spec: None, other: Default::default(),
             metadata: Some(super::ObjectMeta {
                 name: Some(String::from("my-pod")),
                 namespace: Some(String::from("my-namespace")),
@@ -414,11 +691,12 @@ mod tests {
                 annotations: Some(MapType::from([
                     (String::from("annot_1"), String::from("1_annot")),
                     (String::from("annot_in_both"), String::from("1_both_annot"))
-                ]))
+                ])),
+                ..Default::default()
             }),
         };
         let second = Resource {
-            api_version: V1.clone(), kind: POD.clone(), spec: None,
+            api_version: V1.clone(), kind: POD.clone(), spec: None, other: Default::default(),
             metadata: Some(super::ObjectMeta {
                 name: Some(String::from("my-pod")),
                 namespace: Some(String::from("my-namespace")),
@@ -429,7 +707,8 @@ mod tests {
                 annotations: Some(MapType::from([
                     (String::from("annot_2"), String::from("2_annot")),
                     (String::from("annot_in_both"), String::from("2_both_annot"))
-                ]))
+                ])),
+                ..Default::default()
             }),
         };
         println!("First B: {}", first);
@@ -437,7 +716,7 @@ mod tests {
         let merged = first.merge(&second);
         println!("Merged B: {}", merged);
         let expected = Resource {
-            api_version: V1.clone(), kind: POD.clone(), spec: None,
+            api_version: V1.clone(), kind: POD.clone(), spec: None, other: Default::default(),
             metadata: Some(super::ObjectMeta {
                 name: Some(String::from("my-pod")),
                 namespace: Some(String::from("my-namespace")),
@@ -450,7 +729,8 @@ mod tests {
                     (String::from("annot_1"), String::from("1_annot")),
                     (String::from("annot_in_both"), String::from("1_both_annot")),
                     (String::from("annot_2"), String::from("2_annot")),
-                ]))
+                ])),
+                ..Default::default()
             }),
         };
         assert_eq!(expected, merged);
@@ -459,7 +739,7 @@ mod tests {
     #[test]
     fn merge_combine_json_both_specs() {
         let first = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: None,
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
             spec: Some(serde_json::json!({
                 "first": "firstValue",
                 "both-list": [ "one", "two", "three" ],
@@ -471,7 +751,7 @@ mod tests {
         };
         println!("First with Spec: {}", first);
         let second = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: None,
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
             spec: Some(serde_json::json!({
                 "second": "secondValue",
                 "both-list": [ "four", "five" ],
@@ -485,7 +765,7 @@ mod tests {
         let merged = first.merge(&second);
         println!("Merged with Spec: {}", merged);
         let expected = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: None,
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
             spec: Some(serde_json::json!({
                 "first": "firstValue",
                 "second": "secondValue",
@@ -503,7 +783,7 @@ mod tests {
     #[test]
     fn merge_single_to_multiple_json() {
         let first = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: None,
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
             spec: Some(serde_json::json!({
                 "containers": [
                     {
@@ -516,7 +796,7 @@ mod tests {
             }))
         };
         let second = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: None,
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
             spec: Some(serde_json::json!({
                 "containers": {
                     "smells": "terrible"
@@ -525,7 +805,7 @@ mod tests {
         };
         let actual = first.merge(&second);
         let expected = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: None,
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
             spec: Some(serde_json::json!({
                 "containers": [
                     {
@@ -546,6 +826,7 @@ mod tests {
         Resource {
             api_version: String::from("v1"),
             kind: String::from("Pod"),
+            other: Default::default(),
             metadata: Some(super::ObjectMeta {
                 name: Some(String::from("bob")),
                 namespace: Some(String::from("hoskins")),
@@ -556,7 +837,8 @@ mod tests {
                 annotations: Some(MapType::from([
                     (String::from("height"), String::from("short")),
                     (String::from("shape"), String::from("round")),
-                ]))
+                ])),
+                ..Default::default()
             }),
             spec: None,
         }
@@ -583,11 +865,13 @@ mod tests {
         Resource {
             api_version: V1.clone(),
             kind: POD.clone(),
+            other: Default::default(),
             metadata: Some(super::ObjectMeta {
                 name: Some(String::from("wasi-demo")),
                 namespace: None,
                 labels: Some(MapType::from([(String::from("run"), String::from("wasi-demo"))])),
                 annotations: Some(MapType::from([(String::from("module.wasm.image/variant"), String::from("compat-smart"))])),
+                ..Default::default()
             }),
             spec: Some(spec),
         }
@@ -596,7 +880,7 @@ mod tests {
     #[test]
     fn serialize_deserialize_yaml() {
         let start: Resource<serde_yaml::Value> = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: None,
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
             spec: Some(
                 serde_yaml::from_str(r#"
                 first: firstValue
@@ -620,7 +904,7 @@ mod tests {
     #[test]
     fn merge_combine_yaml_both_specs() {
         let first: Resource<serde_yaml::Value> = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: None,
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
             spec: Some(
                 serde_yaml::from_str(r#"
                 first: firstValue
@@ -636,7 +920,7 @@ mod tests {
         };
         println!("First with Spec: {}", first);
         let second = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: None,
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
             spec: Some(
                 serde_yaml::from_str(r#"
                 second: secondValue
@@ -653,7 +937,7 @@ mod tests {
         let merged = first.merge(&second);
         println!("Merged with Spec: {}", merged);
         let expected = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: None,
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
             spec: Some(
                 serde_yaml::from_str(r#"
                 first: firstValue
@@ -682,9 +966,10 @@ mod tests {
                 namespace: Some(String::from("Home")),
                 annotations: Some(MapType::from([ (String::from("annot1"), String::from("value1")) ])),
                 labels: Some(MapType::from([ (String::from("label1"), String::from("labelvalue1")) ])),
+                ..Default::default()
         };
         let yaml_based: Resource<serde_yaml::Value> = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: Some(create_meta()),
+            api_version: V1.clone(), kind: POD.clone(), metadata: Some(create_meta()), other: Default::default(),
             spec: Some(
                 serde_yaml::from_str(r#"
                 first: firstValue
@@ -703,7 +988,7 @@ mod tests {
             ),
         };
         let expected = Resource {
-            api_version: V1.clone(), kind: POD.clone(), metadata: Some(create_meta()),
+            api_version: V1.clone(), kind: POD.clone(), metadata: Some(create_meta()), other: Default::default(),
             spec: Some(serde_json::json!({
                 "first": "firstValue",
                 "second": "secondValue",
@@ -719,4 +1004,431 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn json_round_trip_keeps_other_top_level_fields() {
+        let json = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": { "name": "settings" },
+            "data": { "mode": "fast" },
+            "binaryData": { "blob": "AAEC" },
+            "immutable": true,
+        });
+        let resource = Resource::from_json(&json.to_string()).unwrap();
+        assert_eq!(json, serde_json::to_value(&resource).unwrap());
+    }
+
+    #[test]
+    fn yaml_round_trip_keeps_other_top_level_fields() {
+        let yaml = r#"
+        apiVersion: rbac.authorization.k8s.io/v1
+        kind: RoleBinding
+        metadata:
+          name: readers
+        subjects:
+        - kind: Group
+          name: readers
+        roleRef:
+          kind: Role
+          name: reader
+        "#;
+        let resource = Resource::from_yaml(yaml).unwrap();
+        let end = Resource::from_yaml(&resource.to_yaml().unwrap()).unwrap();
+        assert_eq!(resource, end);
+        let json = serde_json::to_value(resource.convert_to_json()).unwrap();
+        assert_eq!(serde_json::json!({ "kind": "Role", "name": "reader" }), json["roleRef"]);
+        assert_eq!(serde_json::json!([{ "kind": "Group", "name": "readers" }]), json["subjects"]);
+    }
+
+    #[test]
+    fn display_shows_only_identifying_fields() {
+        let secret = Resource::from_json(r#"{
+          "apiVersion": "v1",
+          "kind": "Secret",
+          "metadata": { "name": "db", "namespace": "team-a", "labels": { "app": "db" } },
+          "data": { "password": "c2VjcmV0" }
+        }"#).unwrap();
+        assert_eq!("R(apiVersion:v1,kind:Secret,namespace:team-a,name:db)", secret.to_string());
+        let pod = Resource::from_json(r#"{
+          "apiVersion": "v1",
+          "kind": "Pod",
+          "metadata": { "generateName": "web-" },
+          "spec": { "containers": [] }
+        }"#).unwrap();
+        assert_eq!("R(apiVersion:v1,kind:Pod,generateName:web-)", pod.to_string());
+        let meta = ObjectMeta {
+            name: Some(String::from("db")),
+            namespace: Some(String::from("team-a")),
+            uid: Some(String::from("1234")),
+            annotations: Some(MapType::from([(String::from("token"), String::from("s3cr3t"))])),
+            ..Default::default()
+        };
+        assert_eq!("M(namespace:team-a,name:db,uid:1234)", meta.to_string());
+    }
+
+    #[test]
+    fn merge_combines_other_top_level_fields() {
+        let secret = Resource::from_json(&serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Secret",
+            "type": "Opaque",
+            "stringData": { "user": "admin" },
+            "status": { "phase": "Active" },
+        }).to_string()).unwrap();
+        let template = Resource::from_json(&serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Secret",
+            "type": "kubernetes.io/tls",
+            "stringData": { "user": "other", "password": "changeme" },
+            "immutable": true,
+        }).to_string()).unwrap();
+        let expected = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Secret",
+            "type": "Opaque",
+            "stringData": { "user": "admin", "password": "changeme" },
+            "status": { "phase": "Active" },
+            "immutable": true,
+        });
+        assert_eq!(expected, serde_json::to_value(secret.merge(&template)).unwrap());
+    }
+
+    fn replica_set_pod() -> serde_json::Value {
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": {
+                "generateName": "web-7d4b9c8f5-",
+                "namespace": "shop",
+                "uid": "2b3c6f0e-3a83-4a55-9d0b-7a1f5d0c1e22",
+                "resourceVersion": "123456",
+                "generation": 1,
+                "creationTimestamp": "2023-03-01T10:00:00Z",
+                "labels": { "app": "web", "pod-template-hash": "7d4b9c8f5" },
+                "ownerReferences": [ {
+                    "apiVersion": "apps/v1",
+                    "kind": "ReplicaSet",
+                    "name": "web-7d4b9c8f5",
+                    "uid": "9f1c1c57-1c4f-4a52-8f3e-5c9a0a7e5b10",
+                    "controller": true,
+                    "blockOwnerDeletion": true
+                } ],
+                "finalizers": [ "example.com/cleanup" ],
+                "managedFields": [ {
+                    "manager": "kube-controller-manager",
+                    "operation": "Update",
+                    "apiVersion": "v1",
+                    "fieldsType": "FieldsV1",
+                    "fieldsV1": { "f:metadata": { "f:generateName": {} } }
+                } ],
+                "someFutureField": { "enabled": true }
+            },
+            "spec": { "containers": [ { "name": "web", "image": "nginx" } ] }
+        })
+    }
+
+    #[test]
+    fn json_round_trip_keeps_all_metadata() {
+        let json = replica_set_pod();
+        let resource = Resource::from_json(&json.to_string()).unwrap();
+        assert_eq!(json, serde_json::to_value(&resource).unwrap());
+        let metadata = resource.metadata.unwrap();
+        assert_eq!(Some(String::from("web-7d4b9c8f5-")), metadata.generate_name);
+        assert_eq!("9f1c1c57-1c4f-4a52-8f3e-5c9a0a7e5b10", metadata.owner_references.unwrap()[0].uid);
+    }
+
+    #[test]
+    fn merge_keeps_identity_of_first() {
+        let pod = Resource::from_json(&replica_set_pod().to_string()).unwrap();
+        let template = Resource::from_json(&serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": {
+                "name": "template",
+                "generateName": "template-",
+                "uid": "template-uid",
+                "labels": { "injected": "true" }
+            }
+        }).to_string()).unwrap();
+        let merged = serde_json::to_value(pod.merge(&template)).unwrap();
+        let mut expected = replica_set_pod();
+        expected["metadata"]["labels"]["injected"] = serde_json::json!("true");
+        assert_eq!(expected, merged);
+    }
+
+    #[test]
+    fn merge_finalizers_as_set_union() {
+        let first = Some(vec![String::from("a"), String::from("b")]);
+        let second = Some(vec![String::from("c"), String::from("a")]);
+        let expected = Some(vec![String::from("a"), String::from("b"), String::from("c")]);
+        assert_eq!(expected, super::merge_finalizers(&first, &second));
+        assert_eq!(second, super::merge_finalizers(&None, &second));
+        assert_eq!(first, super::merge_finalizers(&first, &None));
+    }
+
+    #[test]
+    fn merge_owner_references_by_uid() {
+        let reference = |name: &str, uid: &str| super::OwnerReference {
+            api_version: String::from("apps/v1"),
+            kind: String::from("ReplicaSet"),
+            name: String::from(name),
+            uid: String::from(uid),
+            controller: None,
+            block_owner_deletion: None,
+        };
+        let first = Some(vec![reference("first", "1"), reference("other", "2")]);
+        let second = Some(vec![reference("second", "1"), reference("third", "3")]);
+        let expected = Some(vec![reference("first", "1"), reference("other", "2"), reference("third", "3")]);
+        assert_eq!(expected, super::merge_owner_references(&first, &second));
+    }
+
+    #[test]
+    fn merge_lists_by_merge_key() {
+        let first = Resource {
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
+            spec: Some(serde_json::json!({
+                "containers": [
+                    { "name": "app", "ports": [ { "containerPort": 8080 } ] },
+                    { "name": "sidecar", "image": "sidecar:1" }
+                ],
+                "tolerations": [ { "key": "gpu", "effect": "NoSchedule" } ],
+                "args": [ "a" ]
+            }))
+        };
+        let second = Resource {
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
+            spec: Some(serde_json::json!({
+                "containers": [
+                    { "name": "sidecar", "image": "sidecar:2", "env": [ { "name": "MODE", "value": "x" } ] },
+                    { "name": "logger" },
+                    { "image": "unnamed" }
+                ],
+                "tolerations": [ { "key": "gpu", "effect": "NoExecute" }, { "key": "spot" }, { "key": "gpu", "effect": "NoSchedule" } ],
+                "args": [ "a" ]
+            }))
+        };
+        let expected = Resource {
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
+            spec: Some(serde_json::json!({
+                "containers": [
+                    { "name": "app", "ports": [ { "containerPort": 8080 } ] },
+                    { "name": "sidecar", "image": "sidecar:1", "env": [ { "name": "MODE", "value": "x" } ] },
+                    { "name": "logger" },
+                    { "image": "unnamed" }
+                ],
+                "tolerations": [ { "key": "gpu", "effect": "NoSchedule" }, { "key": "gpu", "effect": "NoExecute" }, { "key": "spot" } ],
+                "args": [ "a", "a" ]
+            }))
+        };
+        assert_eq!(expected, first.merge(&second));
+    }
+
+    #[test]
+    fn merge_yaml_lists_by_merge_key() {
+        let first = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          volumes:
+          - name: data
+            emptyDir: {}
+        "#).unwrap();
+        let second = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          volumes:
+          - name: data
+            hostPath:
+              path: /data
+          - name: cache
+            emptyDir: {}
+        "#).unwrap();
+        let expected = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          volumes:
+          - name: data
+            emptyDir: {}
+            hostPath:
+              path: /data
+          - name: cache
+            emptyDir: {}
+        "#).unwrap();
+        assert_eq!(expected, first.merge(&second));
+    }
+
+    #[test]
+    fn merge_with_custom_merge_keys() {
+        let mut options = super::MergeOptions::default();
+        options.merge_keys.insert(String::from("args"), vec![String::from("id")]);
+        options.merge_keys.remove("containers");
+        let first = Resource {
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
+            spec: Some(serde_json::json!({
+                "containers": [ { "name": "app" } ],
+                "args": [ { "id": 1, "value": "one" } ]
+            }))
+        };
+        let second = Resource {
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
+            spec: Some(serde_json::json!({
+                "containers": [ { "name": "app" } ],
+                "args": [ { "id": 1, "extra": true }, { "id": 2 } ]
+            }))
+        };
+        let expected = Resource {
+            api_version: V1.clone(), kind: POD.clone(), metadata: None, other: Default::default(),
+            spec: Some(serde_json::json!({
+                "containers": [ { "name": "app" }, { "name": "app" } ],
+                "args": [ { "id": 1, "value": "one", "extra": true }, { "id": 2 } ]
+            }))
+        };
+        assert_eq!(expected, first.merge_with(&second, &options));
+    }
+
+    fn strategy_options(strategies: &[(&str, super::MergeStrategy)]) -> super::MergeOptions {
+        super::MergeOptions {
+            strategies: strategies.iter().map(|(path, strategy)| (path.to_string(), *strategy)).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_strategies_by_path() {
+        use super::MergeStrategy;
+        let options = strategy_options(&[
+            ("spec.serviceAccountName", MergeStrategy::Replace),
+            ("spec.initContainers", MergeStrategy::Prepend),
+            ("spec.containers", MergeStrategy::Append),
+            ("spec.nodeSelector", MergeStrategy::SkipIfPresent),
+            ("spec.affinity", MergeStrategy::SkipIfPresent),
+            ("data.level", MergeStrategy::Replace),
+        ]);
+        let first = Resource::from_json(&serde_json::json!({
+            "apiVersion": "v1", "kind": "Pod",
+            "spec": {
+                "serviceAccountName": "default",
+                "initContainers": [ { "name": "migrate" } ],
+                "containers": [ { "name": "proxy" }, { "name": "app" } ],
+                "nodeSelector": { "zone": "a" },
+                "affinity": null,
+                "restartPolicy": "Always"
+            },
+            "data": { "level": "debug" }
+        }).to_string()).unwrap();
+        let second = Resource::from_json(&serde_json::json!({
+            "apiVersion": "v1", "kind": "Pod",
+            "spec": {
+                "serviceAccountName": "injected",
+                "initContainers": [ { "name": "wait-for-proxy" } ],
+                "containers": [ { "name": "proxy", "image": "proxy:2" } ],
+                "nodeSelector": { "disk": "ssd" },
+                "affinity": { "nodeAffinity": {} },
+                "restartPolicy": "Never"
+            },
+            "data": { "level": "info" }
+        }).to_string()).unwrap();
+        let expected = serde_json::json!({
+            "apiVersion": "v1", "kind": "Pod",
+            "spec": {
+                "serviceAccountName": "injected",
+                "initContainers": [ { "name": "wait-for-proxy" }, { "name": "migrate" } ],
+                "containers": [ { "name": "app" }, { "name": "proxy", "image": "proxy:2" } ],
+                "nodeSelector": { "zone": "a" },
+                "affinity": { "nodeAffinity": {} },
+                "restartPolicy": "Always"
+            },
+            "data": { "level": "info" }
+        });
+        assert_eq!(expected, serde_json::to_value(first.merge_with(&second, &options)).unwrap());
+    }
+
+    #[test]
+    fn prepend_and_append_merge_items_already_present() {
+        use super::MergeStrategy;
+        let options = strategy_options(&[
+            ("spec.initContainers", MergeStrategy::Prepend),
+            ("spec.containers", MergeStrategy::Append),
+        ]);
+        let first = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          initContainers:
+          - name: migrate
+          - name: init
+            image: init:1
+          containers:
+          - name: sidecar
+          - name: app
+        "#).unwrap();
+        let second = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          initContainers:
+          - name: init
+            image: init:2
+            imagePullPolicy: Always
+          containers:
+          - name: sidecar
+            image: sidecar:1
+        "#).unwrap();
+        let expected = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          initContainers:
+          - name: init
+            image: init:1
+            imagePullPolicy: Always
+          - name: migrate
+          containers:
+          - name: app
+          - name: sidecar
+            image: sidecar:1
+        "#).unwrap();
+        let merged = first.merge_with(&second, &options);
+        assert_eq!(expected, merged);
+        assert_eq!(expected, merged.merge_with(&second, &options));
+        assert_eq!(expected.convert_to_json(), first.convert_to_json().merge_with(&second.convert_to_json(), &options));
+    }
+
+    #[test]
+    fn merge_strategy_applies_to_list_items() {
+        let options = strategy_options(&[("spec.containers.image", super::MergeStrategy::Replace)]);
+        let first = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          containers:
+          - name: app
+            image: app:1
+          - name: proxy
+            image: proxy:1
+        "#).unwrap();
+        let second = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          containers:
+          - name: proxy
+            image: proxy:2
+        "#).unwrap();
+        let expected = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          containers:
+          - name: app
+            image: app:1
+          - name: proxy
+            image: proxy:2
+        "#).unwrap();
+        assert_eq!(expected, first.merge_with(&second, &options));
+        assert_eq!(expected.convert_to_json(), first.convert_to_json().merge_with(&second.convert_to_json(), &options));
+    }
+
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Operator {
+    In,
+    NotIn,
+    Exists,
+    DoesNotExist,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelSelectorRequirement {
+    pub key: String,
+    pub operator: Operator,
+    #[serde(default)]
+    pub values: Vec<String>,
+}
+
+impl LabelSelectorRequirement {
+    fn validate(&self) -> Result<(), String> {
+        match (self.operator, self.values.is_empty()) {
+            (Operator::In | Operator::NotIn, true) =>
+                Err(format!("Selector requirement on '{}' needs values for {:?}", self.key, self.operator)),
+            (Operator::Exists | Operator::DoesNotExist, false) =>
+                Err(format!("Selector requirement on '{}' can't have values for {:?}", self.key, self.operator)),
+            _ => Ok(()),
+        }
+    }
+
+    /// As in Kubernetes, `NotIn` matches when the label is missing altogether
+    fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        let value = labels.get(&self.key);
+        match self.operator {
+            Operator::In => value.map(|value| self.values.contains(value)).unwrap_or(false),
+            Operator::NotIn => value.map(|value| !self.values.contains(value)).unwrap_or(true),
+            Operator::Exists => value.is_some(),
+            Operator::DoesNotExist => value.is_none(),
+        }
+    }
+}
+
+/// A Kubernetes label selector, all of `matchLabels` and `matchExpressions` must match
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelSelector {
+    #[serde(default)]
+    pub match_labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub match_expressions: Vec<LabelSelectorRequirement>,
+}
+
+impl LabelSelector {
+    pub fn validate(&self) -> Result<(), String> {
+        self.match_expressions.iter().try_for_each(|requirement| requirement.validate())
+    }
+
+    pub fn matches(&self, labels: Option<&BTreeMap<String, String>>) -> bool {
+        let empty = BTreeMap::new();
+        let labels = labels.unwrap_or(&empty);
+        self.match_labels.iter().all(|(k, v)| labels.get(k) == Some(v)) &&
+        self.match_expressions.iter().all(|requirement| requirement.matches(labels))
+    }
+}
+
+/// Names, or globs using `*` and `?`, e.g. of namespaces or users. Those starting with `!` exclude
+/// matching names, the others include them, and if there are none every name not excluded is included.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(from = "Vec<String>")]
+pub struct GlobFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl From<Vec<String>> for GlobFilter {
+    fn from(patterns: Vec<String>) -> Self {
+        let (exclude, include): (Vec<String>, Vec<String>) = patterns.into_iter().partition(|pattern| pattern.starts_with('!'));
+        GlobFilter {
+            include,
+            exclude: exclude.into_iter().map(|pattern| pattern[1..].to_string()).collect(),
+        }
+    }
+}
+
+impl GlobFilter {
+    /// A missing name, e.g. the namespace of a cluster scoped resource, only matches filters with just exclusions
+    pub fn matches(&self, name: Option<&str>) -> bool {
+        self.matches_any(name.as_slice())
+    }
+
+    /// Matches when any of the names is included and none are excluded, e.g. for a user's groups
+    pub fn matches_any<S: AsRef<str>>(&self, names: &[S]) -> bool {
+        let matching = |patterns: &[String]| names.iter().any(|name| patterns.iter().any(|pattern| glob_matches(pattern, name.as_ref())));
+        (self.include.is_empty() || matching(&self.include)) && !matching(&self.exclude)
+    }
+}
+
+pub(crate) fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    //Where to resume after the last `*` if the characters following it stop matching
+    let mut backtrack: Option<(usize, usize)> = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    v = matched + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{glob_matches, LabelSelector, GlobFilter};
+
+    fn selector(yaml: &str) -> LabelSelector {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn empty_selector_matches_everything() {
+        assert!(LabelSelector::default().matches(None));
+        assert!(LabelSelector::default().matches(Some(&labels(&[("app", "web")]))));
+    }
+
+    #[test]
+    fn match_labels_must_all_be_equal() {
+        let selector = selector("matchLabels: { app: web, tier: 22 }");
+        assert!(selector.matches(Some(&labels(&[("app", "web"), ("tier", "22"), ("other", "x")]))));
+        assert!(!selector.matches(Some(&labels(&[("app", "web")]))));
+        assert!(!selector.matches(None));
+    }
+
+    #[test]
+    fn match_expressions() {
+        let selector = selector(r#"
+        matchExpressions:
+        - { key: tier, operator: In, values: [ web, api ] }
+        - { key: inject, operator: NotIn, values: [ "false" ] }
+        - { key: app, operator: Exists }
+        - { key: legacy, operator: DoesNotExist }
+        "#);
+        assert!(selector.matches(Some(&labels(&[("tier", "web"), ("app", "a")]))));
+        assert!(selector.matches(Some(&labels(&[("tier", "api"), ("app", "a"), ("inject", "true")]))));
+        assert!(!selector.matches(Some(&labels(&[("tier", "db"), ("app", "a")]))));
+        assert!(!selector.matches(Some(&labels(&[("tier", "web"), ("app", "a"), ("inject", "false")]))));
+        assert!(!selector.matches(Some(&labels(&[("tier", "web")]))));
+        assert!(!selector.matches(Some(&labels(&[("tier", "web"), ("app", "a"), ("legacy", "")]))));
+    }
+
+    #[test]
+    fn validates_values_for_operator() {
+        assert!(selector("matchExpressions: [ { key: a, operator: In } ]").validate().is_err());
+        assert!(selector("matchExpressions: [ { key: a, operator: Exists, values: [ b ] } ]").validate().is_err());
+        assert!(selector("matchExpressions: [ { key: a, operator: NotIn, values: [ b ] } ]").validate().is_ok());
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("team-*", "team-a"));
+        assert!(glob_matches("team-*", "team-"));
+        assert!(!glob_matches("team-*", "teams"));
+        assert!(glob_matches("*-prod", "team-a-prod"));
+        assert!(glob_matches("t?am-*-*d", "team-a-prod"));
+        assert!(!glob_matches("t?am", "tam"));
+        assert!(glob_matches("default", "default"));
+        assert!(!glob_matches("default", "defaults"));
+        assert!(glob_matches("*", ""));
+    }
+
+    #[test]
+    fn namespace_filter() {
+        let filter: GlobFilter = serde_yaml::from_str("[ team-*, default, '!team-secret' ]").unwrap();
+        assert!(filter.matches(Some("team-a")));
+        assert!(filter.matches(Some("default")));
+        assert!(!filter.matches(Some("team-secret")));
+        assert!(!filter.matches(Some("kube-system")));
+        assert!(!filter.matches(None));
+    }
+
+    #[test]
+    fn namespace_filter_with_only_exclusions() {
+        let filter: GlobFilter = serde_yaml::from_str("[ '!kube-*' ]").unwrap();
+        assert!(filter.matches(Some("default")));
+        assert!(!filter.matches(Some("kube-system")));
+        assert!(filter.matches(None));
+    }
+
+    #[test]
+    fn glob_filter_matches_any() {
+        let filter: GlobFilter = serde_yaml::from_str("[ 'system:*', '!system:masters' ]").unwrap();
+        assert!(filter.matches_any(&["developers", "system:authenticated"]));
+        assert!(!filter.matches_any(&["system:authenticated", "system:masters"]));
+        assert!(!filter.matches_any(&["developers"]));
+        assert!(!filter.matches_any::<&str>(&[]));
+    }
+
+    #[test]
+    fn rejects_unknown_operator() {
+        assert!(serde_yaml::from_str::<LabelSelector>("matchExpressions: [ { key: a, operator: Gt } ]").is_err());
+    }
+
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeSet;
+
+use serde::Deserialize;
+use serde_yaml::Value;
+
+/// The key marking a value to be read from elsewhere. It has a `$` so it can't be confused
+/// with the `valueFrom` of a container's env, which templates may well contain.
+const VALUE_FROM: &str = "$valueFrom";
+
+/// Where a `$valueFrom` value comes from, e.g. `{ $valueFrom: { env: SIDECAR_IMAGE, default: sidecar:1 } }`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ValueSource {
+    /// An environment variable of the webhook
+    env: Option<String>,
+    /// A file, e.g. mounted from a ConfigMap or Secret, with surrounding whitespace trimmed
+    file: Option<String>,
+    /// Used if the variable isn't set or the file can't be read
+    default: Option<String>,
+}
+
+impl ValueSource {
+    fn resolve(&self) -> Result<String, String> {
+        let value = match (&self.env, &self.file) {
+            (Some(env), None) => std::env::var(env).ok(),
+            (None, Some(file)) => std::fs::read_to_string(file).ok().map(|contents| contents.trim().to_string()),
+            _ => return Err(format!("{} needs one of env or file", VALUE_FROM)),
+        };
+        value.or_else(|| self.default.clone()).ok_or_else(|| match (&self.env, &self.file) {
+            (Some(env), _) => format!("Environment variable {} isn't set and has no default", env),
+            (_, file) => format!("Unable to read {} and there's no default", file.as_deref().unwrap_or_default()),
+        })
+    }
+}
+
+/// Replaces every `$valueFrom` mapping in the document with the string it refers to,
+/// adding the files referred to, whether or not they could be read, to `files`
+pub fn resolve(value: &mut Value, files: &mut BTreeSet<String>) -> Result<(), String> {
+    match value {
+        Value::Mapping(mapping) if mapping.len() == 1 && mapping.contains_key(VALUE_FROM) => {
+            let source: ValueSource = serde_yaml::from_value(mapping[VALUE_FROM].clone())
+                .map_err(|err| format!("Invalid {}: {}", VALUE_FROM, err))?;
+            files.extend(source.file.clone());
+            *value = Value::String(source.resolve()?);
+            Ok(())
+        },
+        Value::Mapping(mapping) => mapping.iter_mut().try_for_each(|(_, value)| resolve(value, files)),
+        Value::Sequence(values) => values.iter_mut().try_for_each(|value| resolve(value, files)),
+        Value::Tagged(tagged) => resolve(&mut tagged.value, files),
+        _ => Ok(()),
+    }
+}
+
+/// Fails if the document has any `$valueFrom`, for documents that mustn't read the webhook's values
+pub fn reject(value: &Value) -> Result<(), String> {
+    match value {
+        Value::Mapping(mapping) if mapping.contains_key(VALUE_FROM) => Err(format!("{} can only be used in the templates file", VALUE_FROM)),
+        Value::Mapping(mapping) => mapping.iter().try_for_each(|(_, value)| reject(value)),
+        Value::Sequence(values) => values.iter().try_for_each(reject),
+        Value::Tagged(tagged) => reject(&tagged.value),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{reject, resolve};
+
+    fn resolved(yaml: &str) -> Result<serde_yaml::Value, String> {
+        let mut value = serde_yaml::from_str(yaml).unwrap();
+        resolve(&mut value, &mut BTreeSet::new()).map(|_| value)
+    }
+
+    #[test]
+    fn resolves_environment_variables() {
+        std::env::set_var("SOURCES_TEST_IMAGE", "sidecar:1.2");
+        let value = resolved(r#"
+        containers:
+        - name: sidecar
+          image: { $valueFrom: { env: SOURCES_TEST_IMAGE } }
+          env:
+          - name: ENDPOINT
+            value: { $valueFrom: { env: SOURCES_TEST_UNSET, default: "http://collector:4317" } }
+          - name: POD_NAME
+            valueFrom: { fieldRef: { fieldPath: metadata.name } }
+        "#).unwrap();
+        let expected: serde_yaml::Value = serde_yaml::from_str(r#"
+        containers:
+        - name: sidecar
+          image: sidecar:1.2
+          env:
+          - name: ENDPOINT
+            value: "http://collector:4317"
+          - name: POD_NAME
+            valueFrom: { fieldRef: { fieldPath: metadata.name } }
+        "#).unwrap();
+        assert_eq!(expected, value);
+    }
+
+    #[test]
+    fn resolves_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("endpoint");
+        std::fs::write(&file, "http://collector:4317\n").unwrap();
+        let mut value = serde_yaml::from_str(&format!(r#"
+        endpoint: {{ $valueFrom: {{ file: '{}' }} }}
+        token: {{ $valueFrom: {{ file: /does/not/exist, default: none }} }}
+        image: {{ $valueFrom: {{ env: SOURCES_TEST_UNSET, default: sidecar:1 }} }}
+        "#, file.display())).unwrap();
+        let mut files = BTreeSet::new();
+        resolve(&mut value, &mut files).unwrap();
+        assert_eq!(serde_yaml::from_str::<serde_yaml::Value>("{ endpoint: http://collector:4317, token: none, image: sidecar:1 }").unwrap(), value);
+        assert_eq!(BTreeSet::from([file.display().to_string(), String::from("/does/not/exist")]), files);
+    }
+
+    #[test]
+    fn fails_for_missing_values() {
+        let err = resolved("image: { $valueFrom: { env: SOURCES_TEST_MISSING } }").unwrap_err();
+        assert!(err.contains("SOURCES_TEST_MISSING"), "{}", err);
+        let err = resolved("image: { $valueFrom: { file: /does/not/exist } }").unwrap_err();
+        assert!(err.contains("/does/not/exist"), "{}", err);
+        assert!(resolved("image: { $valueFrom: { env: A, file: b } }").is_err());
+        assert!(resolved("image: { $valueFrom: { secret: a } }").is_err());
+    }
+
+    #[test]
+    fn rejects_any_value_from() {
+        let value: serde_yaml::Value = serde_yaml::from_str("env: [ { name: TOKEN, value: { $valueFrom: { file: token } } } ]").unwrap();
+        assert!(reject(&value).is_err());
+        let value: serde_yaml::Value = serde_yaml::from_str("env: [ { name: POD_NAME, valueFrom: { fieldRef: { fieldPath: metadata.name } } } ]").unwrap();
+        assert_eq!(Ok(()), reject(&value));
+    }
+
+}
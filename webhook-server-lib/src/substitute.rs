@@ -0,0 +1,181 @@
+use serde_json::Value;
+
+/// The fields a variable's path can start with. Anything else, e.g. the shell's `${HOME}`, is left as it is.
+const ROOTS: [&str; 9] = ["name", "namespace", "apiVersion", "kind", "metadata", "spec", "status", "data", "stringData"];
+
+/// Whether any string in the value has a `${...}` that may be a variable to substitute
+pub fn has_variables(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s.contains("${"),
+        Value::Array(values) => values.iter().any(has_variables),
+        Value::Object(map) => map.iter().any(|(key, value)| key.contains("${") || has_variables(value)),
+        _ => false,
+    }
+}
+
+/// Replaces `${path}` in every string with the value at that dotted path in `object`, e.g.
+/// `${metadata.labels.app}`, or `${namespace}` and `${name}` for the object's own. Only paths starting
+/// with one of the `ROOTS` are variables, others such as `${JAVA_OPTS}` are kept. A variable without
+/// a value is an error, a string that is only a variable takes the value's type, and `$${` gives `${`.
+pub fn substitute(value: &Value, object: &Value) -> Result<Value, String> {
+    match value {
+        Value::String(s) => substitute_string(s, object),
+        Value::Array(values) => values.iter().map(|value| substitute(value, object)).collect::<Result<_, _>>().map(Value::Array),
+        Value::Object(map) => map.iter()
+            .map(|(key, value)| Ok((as_text(&substitute_string(key, object)?), substitute(value, object)?)))
+            .collect::<Result<_, String>>()
+            .map(Value::Object),
+        _ => Ok(value.clone()),
+    }
+}
+
+fn is_variable(path: &str) -> bool {
+    ROOTS.contains(&path.split('.').next().unwrap_or_default())
+}
+
+fn resolve<'a>(object: &'a Value, path: &str) -> Result<&'a Value, String> {
+    lookup(object, path).ok_or_else(|| format!("Variable ${{{}}} has no value", path))
+}
+
+fn substitute_string(s: &str, object: &Value) -> Result<Value, String> {
+    if let Some(path) = s.strip_prefix("${").and_then(|rest| rest.strip_suffix('}')).map(str::trim).filter(|path| !path.contains('}') && is_variable(path)) {
+        return resolve(object, path).cloned();
+    }
+    let mut result = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        match rest[start..].find('}') {
+            Some(end) => {
+                let path = rest[start + 2..start + end].trim();
+                if is_variable(path) {
+                    result.push_str(&rest[..start]);
+                    result.push_str(&as_text(resolve(object, path)?));
+                } else {
+                    result.push_str(&rest[..start + end + 1]);
+                }
+                rest = &rest[start + end + 1..];
+            },
+            None => break,
+        }
+    }
+    result.push_str(rest);
+    Ok(Value::String(result))
+}
+
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// Follows the dotted path, preferring the longest key at each step so keys
+/// containing dots such as `${metadata.labels.app.kubernetes.io/name}` resolve
+fn lookup<'a>(object: &'a Value, path: &str) -> Option<&'a Value> {
+    match path {
+        "name" | "namespace" => return object.get("metadata").and_then(|meta| meta.get(path)),
+        _ => (),
+    }
+    let segments: Vec<&str> = path.split('.').collect();
+    lookup_segments(object, &segments)
+}
+
+fn lookup_segments<'a>(value: &'a Value, segments: &[&str]) -> Option<&'a Value> {
+    if segments.is_empty() {
+        return Some(value);
+    }
+    match value {
+        Value::Object(map) => (1..=segments.len()).rev()
+            .find_map(|taken| map.get(&segments[..taken].join("."))
+                .and_then(|child| lookup_segments(child, &segments[taken..]))),
+        Value::Array(values) => segments[0].parse::<usize>().ok()
+            .and_then(|index| values.get(index))
+            .and_then(|child| lookup_segments(child, &segments[1..])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{has_variables, substitute};
+
+    fn object() -> serde_json::Value {
+        json!({
+            "metadata": {
+                "name": "web-1",
+                "namespace": "shop",
+                "labels": { "app": "web", "app.kubernetes.io/version": "1.2" },
+            },
+            "spec": { "replicas": 3, "containers": [ { "name": "nginx" } ] },
+        })
+    }
+
+    #[test]
+    fn substitutes_variables_in_strings() {
+        let template = json!({
+            "name": "SERVICE_NAME",
+            "value": "${metadata.labels.app}",
+            "attributes": "service.name=${name},service.namespace=${ namespace },version=${metadata.labels.app.kubernetes.io/version}",
+            "first": "${spec.containers.0.name}",
+        });
+        let expected = json!({
+            "name": "SERVICE_NAME",
+            "value": "web",
+            "attributes": "service.name=web-1,service.namespace=shop,version=1.2",
+            "first": "nginx",
+        });
+        assert_eq!(Ok(expected), substitute(&template, &object()));
+    }
+
+    #[test]
+    fn whole_string_variables_keep_their_type() {
+        assert_eq!(Ok(json!({ "replicas": 3, "text": "3 replicas" })),
+            substitute(&json!({ "replicas": "${spec.replicas}", "text": "${spec.replicas} replicas" }), &object()));
+    }
+
+    #[test]
+    fn missing_values_are_errors() {
+        let err = substitute(&json!([ "team-${metadata.labels.team}" ]), &object()).unwrap_err();
+        assert!(err.contains("${metadata.labels.team}"), "{}", err);
+        assert!(substitute(&json!("${metadata.labels.team}"), &object()).is_err());
+    }
+
+    #[test]
+    fn keeps_shell_style_and_unknown_variables() {
+        let template = json!({
+            "command": [ "sh", "-c", "echo ${HOME} ${metadata.labels.app}" ],
+            "value": "${JAVA_OPTS}",
+            "other": "${unknown.path}",
+        });
+        let expected = json!({
+            "command": [ "sh", "-c", "echo ${HOME} web" ],
+            "value": "${JAVA_OPTS}",
+            "other": "${unknown.path}",
+        });
+        assert_eq!(Ok(expected), substitute(&template, &object()));
+    }
+
+    #[test]
+    fn escapes_and_unterminated() {
+        assert_eq!(Ok(json!("${literal} web")), substitute(&json!("$${literal} ${metadata.labels.app}"), &object()));
+        assert_eq!(Ok(json!("web ${unterminated")), substitute(&json!("${metadata.labels.app} ${unterminated"), &object()));
+    }
+
+    #[test]
+    fn substitutes_keys_and_detects_variables() {
+        let template = json!({ "labels": { "${metadata.labels.app}-sidecar": "true" } });
+        assert!(has_variables(&template));
+        assert!(!has_variables(&json!({ "a": [ 1, "b", { "c": "$d" } ] })));
+        assert_eq!(Ok(json!({ "labels": { "web-sidecar": "true" } })), substitute(&template, &object()));
+    }
+
+}
@@ -1,55 +1,429 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use once_cell::unsync::OnceCell;
 use serde::Deserialize;
 
-use crate::resource::Resource;
+use crate::admission::{AdmissionRequest, Operation};
+use crate::conditions::{self, MatchCondition, MatchConditionConfig};
+use crate::crd::{self, ApiClient, ResourceTemplates};
+use crate::gvk::{GvkMatcher, GvkRules};
+use crate::injection::Injection;
+use crate::loader::{self, Document};
+use crate::policies::{Policy, PolicyConfig};
+use crate::predicate::{Predicate, PredicateConfig};
+use crate::render::BodyTemplate;
+use crate::resource::{MergeKeys, MergeOptions, MergeStrategy, Resource};
+use crate::selector::{GlobFilter, LabelSelector};
+use crate::sources;
+use crate::substitute;
+use crate::watch;
 
 #[derive(Clone)]
 pub struct Template {
+    pub name: String,
+    /// The file, and document, the template came from
+    pub source: Option<String>,
+    pub priority: i32,
     pub resource: Resource<serde_json::Value>,
+    merge_options: MergeOptions,
+    selector: Option<LabelSelector>,
+    namespaces: Option<GlobFilter>,
+    namespace_selector: Option<LabelSelector>,
+    gvk: GvkMatcher,
+    request_filter: RequestFilter,
+    when: Vec<Predicate>,
+    match_conditions: Vec<MatchCondition>,
+    /// Whether any values have `${...}` variables to substitute
+    has_variables: bool,
+    body: Option<BodyTemplate>,
+    /// Only applied to objects naming it with the `templatesKey`
+    opt_in: bool,
+}
+
+/// Labels of each namespace, by namespace name
+pub type NamespaceLabels = BTreeMap<String, BTreeMap<String, String>>;
+
+/// The object being admitted and its request as JSON, for `when`, `matchConditions` and
+/// rendered bodies. Only built if a template needs it.
+struct Variables<'a> {
+    target: &'a Resource<serde_json::Value>,
+    request: Option<&'a AdmissionRequest>,
+    namespace_labels: &'a NamespaceLabels,
+    json: OnceCell<serde_json::Value>,
+}
+
+impl<'a> Variables<'a> {
+    fn new(target: &'a Resource<serde_json::Value>, request: Option<&'a AdmissionRequest>, namespace_labels: &'a NamespaceLabels) -> Variables<'a> {
+        Variables { target, request, namespace_labels, json: OnceCell::new() }
+    }
+
+    fn namespace(&self) -> Option<&'a str> {
+        self.target.metadata.as_ref().and_then(|meta| meta.namespace.as_deref())
+    }
+
+    /// `object`, `oldObject`, `request` and `namespaceObject`, each null if not known
+    fn json(&self) -> &serde_json::Value {
+        self.json.get_or_init(|| serde_json::json!({
+            "object": serde_json::to_value(self.target).unwrap_or_default(),
+            "oldObject": self.request.and_then(|request| request.old_object.as_ref()),
+            "request": self.request.and_then(|request| serde_json::to_value(request).ok()),
+            "namespaceObject": self.namespace().map(|namespace| serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Namespace",
+                "metadata": { "name": namespace, "labels": self.namespace_labels.get(namespace).cloned().unwrap_or_default() },
+            })),
+        }))
+    }
+
+    fn object(&self) -> &serde_json::Value {
+        &self.json()["object"]
+    }
+
+    fn for_conditions(&self) -> conditions::Variables<'_> {
+        let json = self.json();
+        conditions::Variables {
+            object: &json["object"],
+            old_object: &json["oldObject"],
+            request: &json["request"],
+            namespace_object: &json["namespaceObject"],
+        }
+    }
+}
+
+/// The template's name, with where it came from if known
+fn describe(name: &str, source: Option<&str>) -> String {
+    match source {
+        Some(source) => format!("{} ({})", name, source),
+        None => name.to_string(),
+    }
 }
 
 impl Template {
 
-    fn apply_to(&self, resource: &Resource<serde_json::Value>) -> Option<Resource<serde_json::Value>> {
-        if self.matches(resource) {
-            return Some(resource.merge(&self.resource));
+    fn describe(&self) -> String {
+        describe(&self.name, self.source.as_deref())
+    }
+
+    /// Unnamed templates are named after their source and position in it, e.g. `templates.yaml#0`
+    fn construct(index: usize, source: Option<String>, config: ConfigTemplate, merge_options: &MergeOptions) -> Result<Template, String> {
+        let name = config.settings.name.unwrap_or_else(|| match source.as_deref() {
+            Some(source) => format!("{}#{}", source, index),
+            None => format!("templates[{}]", index),
+        });
+        let described = describe(&name, source.as_deref());
+        for selector in config.settings.selector.iter().chain(config.settings.namespace_selector.iter()) {
+            selector.validate().map_err(|err| format!("Template {}: {}", described, err))?;
+        }
+        if let Some(path) = config.settings.strategies.keys().find(|path| *path == "metadata" || path.starts_with("metadata.")) {
+            return Err(format!("Template {}: strategy for {} not supported, metadata is always merged", described, path));
+        }
+        let resource = config.resource.convert_to_json();
+        let gvk = match config.settings.gvk {
+            Some(rules) => GvkMatcher::from_rules(rules).map_err(|err| format!("Template {}: {}", described, err))?,
+            None => GvkMatcher::from_template(&resource.api_version, &resource.kind),
         };
-        None
+        let when = config.settings.when.into_iter()
+            .map(Predicate::compile)
+            .collect::<Result<Vec<Predicate>, String>>()
+            .map_err(|err| format!("Template {}: {}", described, err))?;
+        let match_conditions = config.settings.match_conditions.into_iter()
+            .map(MatchCondition::compile)
+            .collect::<Result<Vec<MatchCondition>, String>>()
+            .map_err(|err| format!("Template {}: {}", described, err))?;
+        let has_variables = serde_json::to_value(&resource)
+            .map(|json| substitute::has_variables(&json))
+            .map_err(|err| format!("Template {}: {}", described, err))?;
+        let body = config.settings.body.as_deref()
+            .map(BodyTemplate::compile)
+            .transpose()
+            .map_err(|err| format!("Template {}: invalid body: {}", described, err))?;
+        Ok(Template {
+            name,
+            source,
+            priority: config.settings.priority,
+            resource,
+            merge_options: MergeOptions { strategies: config.settings.strategies, ..merge_options.clone() },
+            selector: config.settings.selector,
+            namespaces: config.settings.namespaces,
+            namespace_selector: config.settings.namespace_selector,
+            gvk,
+            request_filter: config.settings.request_filter,
+            when,
+            match_conditions,
+            has_variables,
+            body,
+            opt_in: config.settings.opt_in,
+        })
     }
 
-    fn matches(&self, resource: &Resource<serde_json::Value>) -> bool {
-        self.resource.api_version == resource.api_version &&
-        self.resource.kind == resource.kind &&
+    /// Merges the template into `resource`, with any variables resolved against the object being admitted.
+    /// A rendered body is merged after the rest of the template, so loses any conflicts with it.
+    fn apply_to(&self, resource: &Resource<serde_json::Value>, variables: &Variables) -> Result<Resource<serde_json::Value>, String> {
+        let template = if self.has_variables {
+            let substituted = serde_json::to_value(&self.resource)
+                .map_err(|err| err.to_string())
+                .and_then(|template| substitute::substitute(&template, variables.object()))
+                .and_then(|template| serde_json::from_value::<Resource<serde_json::Value>>(template).map_err(|err| err.to_string()))
+                .map_err(|err| format!("Unable to substitute variables: {}", err))?;
+            Cow::Owned(substituted)
+        } else {
+            Cow::Borrowed(&self.resource)
+        };
+        let merged = resource.merge_with(&template, &self.merge_options);
+        match self.body.as_ref() {
+            Some(body) => Ok(merged.merge_with(&self.render(body, variables)?, &self.merge_options)),
+            None => Ok(merged),
+        }
+    }
+
+    fn matches(&self, variables: &Variables) -> bool {
+        let (resource, request, namespace_labels) = (variables.target, variables.request, variables.namespace_labels);
+        let namespace = variables.namespace();
+        self.gvk.matches(&resource.api_version, &resource.kind) &&
         self.resource.metadata.as_ref().map(|meta| {
             meta.namespace.as_ref().map(|template_ns| {
-                resource.metadata.as_ref().map(|rmeta| rmeta.namespace.as_ref()).flatten().map(|rns| {
+                resource.metadata.as_ref().and_then(|rmeta| rmeta.namespace.as_ref()).map(|rns| {
                     template_ns == rns
                 }).unwrap_or(false)
             }).unwrap_or(true)
             &&
             meta.labels.as_ref().map(|template_labels| {
-                resource.metadata.as_ref().map(|rmeta| rmeta.labels.as_ref()).flatten().map(|rlabels| {
+                resource.metadata.as_ref().and_then(|rmeta| rmeta.labels.as_ref()).map(|rlabels| {
                     template_labels.iter().all(|(k, v)| rlabels.get(k) == Some(v))
                 }).unwrap_or(false)
             }).unwrap_or(true)
             &&
             meta.annotations.as_ref().map(|template_annotations| {
-                resource.metadata.as_ref().map(|rmeta| rmeta.annotations.as_ref()).flatten().map(|rannotations| {
+                resource.metadata.as_ref().and_then(|rmeta| rmeta.annotations.as_ref()).map(|rannotations| {
                     template_annotations.iter().all(|(k, v)| rannotations.get(k) == Some(v))
                 }).unwrap_or(false)
             }).unwrap_or(true)
-        }).unwrap_or(true)
+        }).unwrap_or(true) &&
+        self.selector.as_ref().map(|selector| {
+            selector.matches(resource.metadata.as_ref().and_then(|rmeta| rmeta.labels.as_ref()))
+        }).unwrap_or(true) &&
+        self.namespaces.as_ref().map(|namespaces| namespaces.matches(namespace)).unwrap_or(true) &&
+        self.namespace_selector.as_ref().map(|selector| {
+            namespace.is_some() && selector.matches(namespace.and_then(|namespace| namespace_labels.get(namespace)))
+        }).unwrap_or(true) &&
+        self.request_filter.matches(request) &&
+        self.conditions_match(variables)
     }
 
+    /// Evaluates the `when` predicates and then the CEL `matchConditions`, any errors count as not matching
+    fn conditions_match(&self, variables: &Variables) -> bool {
+        self.when.iter().all(|predicate| predicate.matches(variables.object())) &&
+        self.match_conditions.iter().all(|condition| condition.matches(&variables.for_conditions()).unwrap_or_else(|err| {
+            log::warn!("Template {} not applied: {}", self.describe(), err);
+            false
+        }))
+    }
+
+    /// Renders the body against the variables, taking the apiVersion and kind from the template
+    fn render(&self, body: &BodyTemplate, variables: &Variables) -> Result<Resource<serde_json::Value>, String> {
+        let mut mapping = body.render(variables.json())?;
+        for (key, value) in [("apiVersion", &self.resource.api_version), ("kind", &self.resource.kind)] {
+            if !mapping.contains_key(key) {
+                mapping.insert(serde_yaml::Value::from(key), serde_yaml::Value::from(value.as_str()));
+            }
+        }
+        serde_yaml::to_string(&mapping)
+            .and_then(|yaml| Resource::from_yaml(&yaml))
+            .map(|resource| resource.convert_to_json())
+            .map_err(|err| format!("Rendered body isn't a resource: {}", err))
+    }
+
+}
+
+/// Restrictions on the admission request, applied without a request (e.g. in tests) only when there are none
+#[derive(Deserialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RequestFilter {
+    operations: Option<Vec<Operation>>,
+    /// Usernames or globs, e.g. `"!system:serviceaccount:kube-system:*"`
+    users: Option<GlobFilter>,
+    /// Matched against all of the user's groups
+    user_groups: Option<GlobFilter>,
+    dry_run: Option<bool>,
+}
+
+impl RequestFilter {
+    const KEYS: &'static [&'static str] = &["operations", "users", "userGroups", "dryRun"];
+
+    fn is_empty(&self) -> bool {
+        self.operations.is_none() && self.users.is_none() && self.user_groups.is_none() && self.dry_run.is_none()
+    }
+
+    fn matches(&self, request: Option<&AdmissionRequest>) -> bool {
+        let request = match request {
+            Some(request) => request,
+            None => return self.is_empty(),
+        };
+        let user_info = request.user_info.clone().unwrap_or_default();
+        self.operations.as_ref().map(|operations| {
+            request.operation.map(|operation| operations.contains(&operation)).unwrap_or(false)
+        }).unwrap_or(true) &&
+        self.users.as_ref().map(|users| users.matches(user_info.username.as_deref())).unwrap_or(true) &&
+        self.user_groups.as_ref().map(|groups| groups.matches_any(&user_info.groups)).unwrap_or(true) &&
+        self.dry_run.map(|dry_run| request.dry_run.unwrap_or(false) == dry_run).unwrap_or(true)
+    }
+}
+
+/// Which of the matching templates are applied to a resource
+#[derive(Deserialize, Default, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum ApplyMode {
+    /// Only the highest priority matching template
+    #[default]
+    First,
+    /// Every matching template, highest priority first
+    All,
+}
+
+/// The result of applying templates to a resource
+#[derive(Debug, PartialEq, Clone)]
+pub struct Applied {
+    pub resource: Resource<serde_json::Value>,
+    /// Names of the templates applied, in the order they were applied
+    pub templates: Vec<String>,
+    /// Why any matching templates couldn't be applied, e.g. their body failed to render
+    pub errors: Vec<String>,
 }
 
 #[derive(Clone)]
 pub struct Templates {
+    /// Sorted highest priority first, equal priorities keep their order in the file
     pub templates: Vec<Template>,
+    apply_mode: ApplyMode,
+    namespace_labels: NamespaceLabels,
+    injection: Injection,
+    /// Checked by the validating webhook
+    pub policies: Vec<Policy>,
+    /// The files `$valueFrom` refers to, watched along with the templates files
+    value_files: BTreeSet<String>,
+}
+
+/// Settings given alongside the resource in each template
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct TemplateSettings {
+    /// Reported when the template is applied, must be unique
+    name: Option<String>,
+    /// Higher priority templates are applied first, so win any conflicts under the default merge
+    #[serde(default)]
+    priority: i32,
+    /// How the template's values are merged, keyed by dotted path e.g. `spec.initContainers: prepend`.
+    /// Not available under `metadata`, which is always merged.
+    #[serde(default)]
+    strategies: BTreeMap<String, MergeStrategy>,
+    /// Labels the resource must have, with `matchLabels` and `matchExpressions` as in Kubernetes
+    selector: Option<LabelSelector>,
+    /// Namespace names or globs, e.g. `[ team-*, "!team-secret" ]`
+    namespaces: Option<GlobFilter>,
+    /// Labels the resource's namespace must have, as given in `namespaceLabels`
+    namespace_selector: Option<LabelSelector>,
+    /// Groups, versions and kinds to match instead of the template's own apiVersion and kind
+    gvk: Option<GvkRules>,
+    /// JSONPath predicates on the resource that must all hold
+    #[serde(default)]
+    when: Vec<PredicateConfig>,
+    /// CEL expressions with `object`, `oldObject`, `request` and `namespaceObject` that must all be true
+    #[serde(default)]
+    match_conditions: Vec<MatchConditionConfig>,
+    /// A minijinja template rendering YAML to merge, with the same variables as `matchConditions`
+    body: Option<String>,
+    /// Only applied to objects naming the template with the injection `templatesKey`
+    #[serde(default)]
+    opt_in: bool,
+    #[serde(flatten)]
+    request_filter: RequestFilter,
+}
+
+impl TemplateSettings {
+    const KEYS: &'static [&'static str] = &["name", "priority", "strategies", "selector", "namespaces", "namespaceSelector", "gvk", "when", "matchConditions", "body", "optIn"];
+}
+
+struct ConfigTemplate {
+    settings: TemplateSettings,
+    resource: Resource<serde_yaml::Value>,
 }
 
+/// The settings are split out by hand and both parts re-read from text, as both
+/// `#[serde(flatten)]` and `serde_yaml::from_value` lose the yaml handling of unquoted
+/// scalars, e.g. numeric label values
+impl<'de> Deserialize<'de> for ConfigTemplate {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut mapping = serde_yaml::Mapping::deserialize(deserializer)?;
+        let settings = TemplateSettings::KEYS.iter().chain(RequestFilter::KEYS)
+            .filter_map(|key| mapping.remove(*key).map(|value| (serde_yaml::Value::from(*key), value)))
+            .collect::<serde_yaml::Mapping>();
+        if settings.contains_key("gvk") {
+            //The template's apiVersion and kind aren't needed to match, and are never merged
+            for key in ["apiVersion", "kind"] {
+                if !mapping.contains_key(key) {
+                    mapping.insert(serde_yaml::Value::from(key), serde_yaml::Value::from("*"));
+                }
+            }
+        }
+        let resource = serde_yaml::to_string(&mapping)
+            .and_then(|yaml| Resource::from_yaml(&yaml))
+            .map_err(serde::de::Error::custom)?;
+        let settings = serde_yaml::to_string(&settings)
+            .and_then(|yaml| serde_yaml::from_str(&yaml))
+            .map_err(serde::de::Error::custom)?;
+        Ok(ConfigTemplate { settings, resource })
+    }
+}
+
+#[serde_with::serde_as]
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct ConfigTemplates {
-    templates: Vec<Resource<serde_yaml::Value>>
+    #[serde(default)]
+    templates: Vec<ConfigTemplate>,
+    /// Added to, or replacing, the default strategic merge keys
+    #[serde(default)]
+    #[serde_as(as = "std::collections::BTreeMap<_, serde_with::OneOrMany<_>>")]
+    merge_keys: MergeKeys,
+    apply_mode: Option<ApplyMode>,
+    /// Namespace labels for templates with a `namespaceSelector`
+    #[serde(default)]
+    namespace_labels: NamespaceLabels,
+    injection: Option<Injection>,
+    #[serde(default)]
+    policies: Vec<PolicyConfig>,
+}
+
+/// Takes a setting that can only be given once from whichever document gives it
+fn set_once<T>(setting: &mut Option<(T, Option<String>)>, value: Option<T>, source: &Option<String>, key: &str) -> Result<(), String> {
+    match (setting.as_ref(), value) {
+        (Some((_, first)), Some(_)) => Err(format!("{} is set in both {} and {}",
+            key, first.as_deref().unwrap_or("?"), source.as_deref().unwrap_or("?"))),
+        (None, Some(value)) => {
+            *setting = Some((value, source.clone()));
+            Ok(())
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Adds the entries of a map setting, each of which can only be set once
+fn extend_once<V: PartialEq>(setting: &mut BTreeMap<String, (V, Option<String>)>, values: BTreeMap<String, V>, source: &Option<String>, key: &str) -> Result<(), String> {
+    for (name, value) in values {
+        match setting.get(&name) {
+            Some((first_value, first)) if *first_value != value => return Err(format!("{} {} is set in both {} and {}",
+                key, name, first.as_deref().unwrap_or("?"), source.as_deref().unwrap_or("?"))),
+            Some(_) => {},
+            None => {
+                setting.insert(name, (value, source.clone()));
+            },
+        }
+    }
+    Ok(())
 }
 
 impl Templates {
@@ -57,29 +431,193 @@ impl Templates {
         self.templates.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    pub(crate) fn names(&self) -> BTreeSet<String> {
+        self.templates.iter().map(|template| template.name.clone()).collect()
+    }
+
     pub fn apply_to(&self, target: &Resource<serde_json::Value>) -> Option<Resource<serde_json::Value>> {
-        self.templates.iter()
-            .filter_map(|template| template.apply_to(target))
-            .next()
+        self.apply(target, None).map(|applied| applied.resource)
     }
 
-    fn construct_templates(yaml: &str) -> Result<Templates, String> {
-        let templates_result = serde_yaml::from_str(yaml)
-            .map(|config_templates: ConfigTemplates| Templates {
-                    templates: config_templates.templates.iter()
-                        .map(|resource| Template { resource: resource.convert_to_json() })
-                        .collect()
+    /// Applies the matching templates according to the apply mode. Templates are matched
+    /// against the target as it arrived, not as modified by earlier templates.
+    /// A template that fails to apply is skipped, with the error returned alongside the result.
+    /// Objects and namespaces can opt out, or in, as configured by `injection`.
+    pub fn apply(&self, target: &Resource<serde_json::Value>, request: Option<&AdmissionRequest>) -> Option<Applied> {
+        let variables = Variables::new(target, request, &self.namespace_labels);
+        let namespace_labels = variables.namespace().and_then(|namespace| self.namespace_labels.get(namespace));
+        if let Some(reason) = self.injection.skip_reason(target.metadata.as_ref(), namespace_labels) {
+            log::info!("Not applying templates to {}: {}", target, reason);
+            return None;
+        }
+        let requested = self.injection.requested_templates(target.metadata.as_ref());
+        let mut applied: Option<Applied> = None;
+        for template in self.templates.iter()
+            .filter(|template| requested.as_ref().map(|names| names.contains(&template.name)).unwrap_or(!template.opt_in))
+            .filter(|template| template.matches(&variables)) {
+            let mut current = applied.take().unwrap_or_else(|| Applied { resource: target.clone(), templates: vec![], errors: vec![] });
+            match template.apply_to(&current.resource, &variables) {
+                Ok(resource) => {
+                    current.resource = resource;
+                    current.templates.push(template.name.clone());
+                },
+                Err(err) => {
+                    log::warn!("Template {} not applied: {}", template.describe(), err);
+                    current.errors.push(format!("Template {} not applied: {}", template.name, err));
+                },
+            }
+            applied = Some(current);
+            if self.apply_mode == ApplyMode::First {
+                break;
+            }
+        }
+        applied
+    }
+
+    #[cfg(test)]
+    pub(crate) fn construct_templates(yaml: &str) -> Result<Templates, String> {
+        Self::construct_documents(loader::documents(None, yaml)?)
+    }
+
+    /// Combines the documents, in order, into one set of templates. Templates, policies, merge keys and
+    /// namespace labels are gathered from all of them, while `applyMode` and `injection` can only be set once,
+    /// as can the merge keys of each list and the labels of each namespace.
+    /// Any `$valueFrom` environment variables and files of trusted documents are read here, so again whenever
+    /// the templates are reloaded, while untrusted documents using `$valueFrom` fail to load.
+    pub(crate) fn construct_documents(documents: Vec<Document>) -> Result<Templates, String> {
+        let mut config_templates = Vec::new();
+        let mut merge_keys = BTreeMap::new();
+        let mut apply_mode = None;
+        let mut namespace_labels = BTreeMap::new();
+        let mut injection = None;
+        let mut policies = Vec::new();
+        let mut value_files = BTreeSet::new();
+        for Document { source, mut value, trusted } in documents {
+            let in_source = |err: String| match &source {
+                Some(source) => format!("{}: {}", source, err),
+                None => err,
+            };
+            if trusted {
+                sources::resolve(&mut value, &mut value_files)
+            } else {
+                sources::reject(&value)
+            }.map_err(in_source)?;
+            //Re-read from text to keep the yaml handling of unquoted scalars, as for ConfigTemplate
+            let config: ConfigTemplates = serde_yaml::to_string(&value)
+                .and_then(|yaml| serde_yaml::from_str(&yaml))
+                .map_err(|err| in_source(err.to_string()))?;
+            config_templates.extend(config.templates.into_iter().enumerate().map(|(index, template)| (index, source.clone(), template)));
+            extend_once(&mut merge_keys, config.merge_keys, &source, "mergeKeys")?;
+            set_once(&mut apply_mode, config.apply_mode, &source, "applyMode")?;
+            extend_once(&mut namespace_labels, config.namespace_labels, &source, "namespaceLabels")?;
+            set_once(&mut injection, config.injection, &source, "injection")?;
+            for policy in config.policies {
+                policies.push(Policy::construct(policy).map_err(in_source)?);
+            }
+        }
+        let merge_options = Self::merge_options(merge_keys.into_iter().map(|(field, (keys, _))| (field, keys)).collect());
+        let mut templates = config_templates.into_iter()
+            .map(|(index, source, template)| Template::construct(index, source, template, &merge_options))
+            .collect::<Result<Vec<Template>, String>>()?;
+        templates.sort_by_key(|template| std::cmp::Reverse(template.priority));
+        if let Some((first, duplicate)) = templates.iter().enumerate()
+            .find_map(|(index, template)| templates[..index].iter().find(|t| t.name == template.name).map(|first| (first, template))) {
+            return Err(match (&first.source, &duplicate.source) {
+                (Some(first_source), Some(source)) => format!("Template name '{}' is used more than once, in {} and {}", duplicate.name, first_source, source),
+                _ => format!("Template name '{}' is used more than once", duplicate.name),
             });
-        match templates_result {
-            Ok(templates) => Ok(templates),
-            Err(err) => Err(err.to_string()),
+        }
+        Ok(Templates {
+            templates,
+            apply_mode: apply_mode.map(|(apply_mode, _)| apply_mode).unwrap_or_default(),
+            namespace_labels: namespace_labels.into_iter().map(|(namespace, (labels, _))| (namespace, labels)).collect(),
+            injection: injection.map(|(injection, _)| injection).unwrap_or_default(),
+            policies,
+            value_files,
+        })
+    }
+
+    /// Every way the resource breaks the policies
+    pub fn validate(&self, target: &Resource<serde_json::Value>) -> Vec<String> {
+        self.policies.iter().flat_map(|policy| policy.violations(target)).collect()
+    }
+
+    fn merge_options(merge_keys: MergeKeys) -> MergeOptions {
+        let mut options = MergeOptions::default();
+        options.merge_keys.extend(merge_keys);
+        options
+    }
+
+    /// Loads the templates from a file, every YAML file in a directory, or the files matching a glob.
+    /// Files can have several `---` separated documents.
+    pub fn from_file(path: &str) -> Result<Templates, String> {
+        let templates = Self::construct_documents(loader::load(path)?)?;
+        for template in templates.templates.iter() {
+            log::info!("Loaded template {}", template.describe());
+        }
+        Ok(templates)
+    }
+
+    /// Swaps in the templates from `file_name` whenever it, the files in it, or the files `$valueFrom`
+    /// refers to change. If the new templates fail to load the error is logged and the previous templates are kept.
+    pub fn watch(self, file_name: &str, interval: Duration) -> Arc<ArcSwap<Templates>> {
+        let templates = Arc::new(ArcSwap::from_pointee(self));
+        let reload = Self::reloader(file_name, ResourceTemplates::default(), templates.clone());
+        watch::watch_listed_files(Self::watched_files(file_name, templates.clone()), interval, reload);
+        templates
+    }
+
+    /// As `watch`, also adding the templates of the MutationTemplates the client lists and watches.
+    /// These are swapped in once first listed, and then whenever they're added, modified or deleted.
+    pub fn watch_with_resources(self, file_name: &str, interval: Duration, client: ApiClient) -> Arc<ArcSwap<Templates>> {
+        let templates = Arc::new(ArcSwap::from_pointee(self));
+        let resources = ResourceTemplates::default();
+        let reload = Self::reloader(file_name, resources.clone(), templates.clone());
+        watch::watch_listed_files(Self::watched_files(file_name, templates.clone()), interval, reload.clone());
+        crd::watch_templates(client, resources, interval, reload);
+        templates
+    }
+
+    /// Lists the templates files and the files `$valueFrom` refers to in the current templates
+    fn watched_files(file_name: &str, templates: Arc<ArcSwap<Templates>>) -> impl Fn() -> Vec<String> + Send + 'static {
+        let path = file_name.to_string();
+        move || {
+            let mut files = loader::files(&path).unwrap_or_default();
+            files.extend(templates.load().value_files.iter().cloned());
+            files
         }
     }
 
-    pub fn from_file(file_name: &str) -> Result<Templates, String> {
-      std::fs::read_to_string(file_name)
-        .map_err(|err| err.to_string())
-        .and_then(|s| Self::construct_templates(&s))
+    /// Loads the templates from the file and the resources, swapping them in if they load. The file
+    /// and resource watchers share the reloader, which runs one reload at a time, so an older
+    /// snapshot can't be swapped in over a newer one.
+    fn reloader(file_name: &str, resources: ResourceTemplates, templates: Arc<ArcSwap<Templates>>) -> impl Fn() + Clone + Send + Sync + 'static {
+        let file_name = file_name.to_string();
+        let reloading = Arc::new(Mutex::new(()));
+        move || {
+            let _reloading = reloading.lock().unwrap();
+            let loaded = loader::load(&file_name).and_then(|mut documents| {
+                //Loaded alone first, so resources can't use the names of the file's templates
+                let from_file = Self::construct_documents(documents.clone())?;
+                let from_resources = resources.documents(&from_file.names());
+                if from_resources.is_empty() {
+                    return Ok(from_file);
+                }
+                documents.extend(from_resources);
+                Self::construct_documents(documents)
+            });
+            match loaded {
+                Ok(new_templates) => {
+                    log::info!("Reloaded {} template(s) from {}", new_templates.len(), file_name);
+                    templates.store(Arc::new(new_templates));
+                },
+                Err(err) => log::error!("Failed to reload templates from {}, keeping the previous ones: {}", file_name, err),
+            }
+        }
     }
 
 }
@@ -87,8 +625,13 @@ impl Templates {
 #[cfg(test)]
 mod tests {
 
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::admission::AdmissionRequest;
     use crate::resource::Resource;
     use crate::templates::Templates;
+    use crate::watch::eventually;
 
     #[test]
     fn load_and_apply_config() {
@@ -244,6 +787,797 @@ mod tests {
         assert_eq!(true, templates.apply_to(&non_matching2).is_none());
     }
 
+    #[test]
+    fn applies_template_to_other_top_level_fields() {
+        let yaml = r#"
+        templates:
+        - apiVersion: rbac.authorization.k8s.io/v1
+          kind: ClusterRole
+          rules:
+          - apiGroups: [ "" ]
+            resources: [ events ]
+            verbs: [ create ]
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let role = Resource::from_yaml(r#"
+        apiVersion: rbac.authorization.k8s.io/v1
+        kind: ClusterRole
+        metadata:
+          name: reader
+        rules:
+        - apiGroups: [ "" ]
+          resources: [ pods ]
+          verbs: [ get, list ]
+        "#).unwrap().convert_to_json();
+        let expected = Resource::from_yaml(r#"
+        apiVersion: rbac.authorization.k8s.io/v1
+        kind: ClusterRole
+        metadata:
+          name: reader
+        rules:
+        - apiGroups: [ "" ]
+          resources: [ pods ]
+          verbs: [ get, list ]
+        - apiGroups: [ "" ]
+          resources: [ events ]
+          verbs: [ create ]
+        "#).unwrap().convert_to_json();
+        assert_eq!(Some(expected), templates.apply_to(&role));
+    }
+
+    #[test]
+    fn merges_lists_by_merge_key() {
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          spec:
+            containers:
+            - name: sidecar
+              image: sidecar:2
+              volumeMounts:
+              - name: shared
+                mountPath: /shared
+            volumes:
+            - name: shared
+              emptyDir: {}
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let pod = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          containers:
+          - name: app
+            image: app:1
+          - name: sidecar
+            image: sidecar:1
+            volumeMounts:
+            - name: other
+              mountPath: /shared
+        "#).unwrap().convert_to_json();
+        let expected = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          containers:
+          - name: app
+            image: app:1
+          - name: sidecar
+            image: sidecar:1
+            volumeMounts:
+            - name: other
+              mountPath: /shared
+          volumes:
+          - name: shared
+            emptyDir: {}
+        "#).unwrap().convert_to_json();
+        assert_eq!(Some(expected), templates.apply_to(&pod));
+    }
+
+    #[test]
+    fn merge_keys_extended_from_file() {
+        let yaml = r#"
+        mergeKeys:
+          sidecars: id
+          ports: port
+        templates:
+        - apiVersion: example.com/v1
+          kind: Mesh
+          spec:
+            sidecars:
+            - id: proxy
+              enabled: true
+            ports:
+            - port: 80
+              name: http
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let mesh = Resource::from_yaml(r#"
+        apiVersion: example.com/v1
+        kind: Mesh
+        spec:
+          sidecars:
+          - id: proxy
+          ports:
+          - port: 80
+            containerPort: 8080
+        "#).unwrap().convert_to_json();
+        let expected = Resource::from_yaml(r#"
+        apiVersion: example.com/v1
+        kind: Mesh
+        spec:
+          sidecars:
+          - id: proxy
+            enabled: true
+          ports:
+          - port: 80
+            containerPort: 8080
+            name: http
+        "#).unwrap().convert_to_json();
+        assert_eq!(Some(expected), templates.apply_to(&mesh));
+    }
+
+    #[test]
+    fn applies_template_strategies() {
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          strategies:
+            spec.initContainers: prepend
+            spec.containers.imagePullPolicy: replace
+            spec.dnsPolicy: skip-if-present
+          spec:
+            initContainers:
+            - name: wait-for-proxy
+            containers:
+              imagePullPolicy: Always
+            dnsPolicy: None
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let pod = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          initContainers:
+          - name: migrate
+          containers:
+          - name: app
+            imagePullPolicy: IfNotPresent
+          dnsPolicy: ClusterFirst
+        "#).unwrap().convert_to_json();
+        let expected = Resource::from_yaml(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          initContainers:
+          - name: wait-for-proxy
+          - name: migrate
+          containers:
+          - name: app
+            imagePullPolicy: Always
+          dnsPolicy: ClusterFirst
+        "#).unwrap().convert_to_json();
+        assert_eq!(Some(expected), templates.apply_to(&pod));
+    }
+
+    #[test]
+    fn rejects_unknown_strategy() {
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          strategies:
+            spec.containers: shuffle
+        "#;
+        let err = Templates::construct_templates(yaml).err().unwrap();
+        assert!(err.contains("shuffle"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_metadata_strategies() {
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          strategies:
+            metadata.labels: replace
+          metadata:
+            labels:
+              app: injected
+        "#;
+        let err = Templates::construct_templates(yaml).err().unwrap();
+        assert!(err.contains("metadata.labels"), "{}", err);
+    }
+
+    fn pod(yaml: &str) -> Resource<serde_json::Value> {
+        Resource::from_yaml(yaml).unwrap().convert_to_json()
+    }
+
+    #[test]
+    fn first_mode_applies_highest_priority_match() {
+        let yaml = r#"
+        templates:
+        - name: low
+          apiVersion: v1
+          kind: Pod
+          spec:
+            dnsPolicy: Default
+        - name: high
+          priority: 10
+          apiVersion: v1
+          kind: Pod
+          spec:
+            dnsPolicy: None
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let applied = templates.apply(&pod("{ apiVersion: v1, kind: Pod }"), None).unwrap();
+        assert_eq!(vec![String::from("high")], applied.templates);
+        assert_eq!(pod("{ apiVersion: v1, kind: Pod, spec: { dnsPolicy: None } }"), applied.resource);
+    }
+
+    #[test]
+    fn all_mode_folds_every_match_in_priority_order() {
+        let yaml = r#"
+        applyMode: all
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          spec:
+            dnsPolicy: Default
+            hostNetwork: true
+        - name: logging
+          priority: 5
+          apiVersion: v1
+          kind: Pod
+          spec:
+            dnsPolicy: None
+            containers:
+            - name: fluentd
+        - name: services
+          apiVersion: v1
+          kind: Service
+          spec:
+            type: ClusterIP
+        - name: metrics
+          priority: 5
+          apiVersion: v1
+          kind: Pod
+          spec:
+            containers:
+            - name: exporter
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let applied = templates.apply(&pod(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          containers:
+          - name: app
+        "#), None).unwrap();
+        assert_eq!(vec!["logging", "metrics", "templates[0]"], applied.templates);
+        let expected = pod(r#"
+        apiVersion: v1
+        kind: Pod
+        spec:
+          containers:
+          - name: app
+          - name: fluentd
+          - name: exporter
+          dnsPolicy: None
+          hostNetwork: true
+        "#);
+        assert_eq!(expected, applied.resource);
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let yaml = r#"
+        templates:
+        - name: sidecar
+          apiVersion: v1
+          kind: Pod
+        - name: sidecar
+          apiVersion: v1
+          kind: Service
+        "#;
+        let err = Templates::construct_templates(yaml).err().unwrap();
+        assert!(err.contains("sidecar"), "{}", err);
+    }
+
+    #[test]
+    fn filters_by_selector() {
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          selector:
+            matchLabels:
+              version: 2
+            matchExpressions:
+            - key: tier
+              operator: In
+              values: [ web, api ]
+            - key: inject
+              operator: NotIn
+              values: [ "false" ]
+          spec:
+            dnsPolicy: None
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let matching = pod("{ apiVersion: v1, kind: Pod, metadata: { labels: { tier: api, version: 2 } } }");
+        let wrong_tier = pod("{ apiVersion: v1, kind: Pod, metadata: { labels: { tier: db, version: 2 } } }");
+        let opted_out = pod("{ apiVersion: v1, kind: Pod, metadata: { labels: { tier: web, version: 2, inject: 'false' } } }");
+        let no_labels = pod("{ apiVersion: v1, kind: Pod }");
+        assert!(templates.apply_to(&matching).is_some());
+        assert!(templates.apply_to(&wrong_tier).is_none());
+        assert!(templates.apply_to(&opted_out).is_none());
+        assert!(templates.apply_to(&no_labels).is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_selector() {
+        let yaml = r#"
+        templates:
+        - name: web
+          apiVersion: v1
+          kind: Pod
+          selector:
+            matchExpressions:
+            - key: tier
+              operator: In
+        "#;
+        let err = Templates::construct_templates(yaml).err().unwrap();
+        assert!(err.contains("web") && err.contains("tier"), "{}", err);
+    }
+
+    #[test]
+    fn filters_by_namespace_globs() {
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          namespaces: [ team-*, default, "!team-secret" ]
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let in_namespace = |namespace: &str| pod(&format!("{{ apiVersion: v1, kind: Pod, metadata: {{ namespace: {} }} }}", namespace));
+        assert!(templates.apply_to(&in_namespace("team-a")).is_some());
+        assert!(templates.apply_to(&in_namespace("default")).is_some());
+        assert!(templates.apply_to(&in_namespace("team-secret")).is_none());
+        assert!(templates.apply_to(&in_namespace("kube-system")).is_none());
+        assert!(templates.apply_to(&pod("{ apiVersion: v1, kind: Pod }")).is_none());
+    }
+
+    #[test]
+    fn filters_by_namespace_selector() {
+        let yaml = r#"
+        namespaceLabels:
+          team-a:
+            env: prod
+          team-b:
+            env: dev
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          namespaceSelector:
+            matchLabels:
+              env: prod
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let in_namespace = |namespace: &str| pod(&format!("{{ apiVersion: v1, kind: Pod, metadata: {{ namespace: {} }} }}", namespace));
+        assert!(templates.apply_to(&in_namespace("team-a")).is_some());
+        assert!(templates.apply_to(&in_namespace("team-b")).is_none());
+        assert!(templates.apply_to(&in_namespace("unknown")).is_none());
+        assert!(templates.apply_to(&pod("{ apiVersion: v1, kind: Pod }")).is_none());
+    }
+
+    #[test]
+    fn matches_api_version_globs() {
+        let yaml = r#"
+        templates:
+        - apiVersion: apps/*
+          kind: Deployment
+          spec:
+            revisionHistoryLimit: 3
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        assert!(templates.apply_to(&pod("{ apiVersion: apps/v1beta2, kind: Deployment }")).is_some());
+        assert!(templates.apply_to(&pod("{ apiVersion: apps/v1, kind: Deployment }")).is_some());
+        assert!(templates.apply_to(&pod("{ apiVersion: extensions/v1beta1, kind: Deployment }")).is_none());
+    }
+
+    #[test]
+    fn matches_gvk_rules() {
+        let yaml = r#"
+        templates:
+        - gvk:
+            groups: [ apps ]
+            versions: [ ">=v1beta2" ]
+            kinds: [ Deployment, StatefulSet ]
+          spec:
+            revisionHistoryLimit: 3
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let expected = pod("{ apiVersion: apps/v1, kind: StatefulSet, spec: { revisionHistoryLimit: 3 } }");
+        assert_eq!(Some(expected), templates.apply_to(&pod("{ apiVersion: apps/v1, kind: StatefulSet }")));
+        assert!(templates.apply_to(&pod("{ apiVersion: apps/v1beta2, kind: Deployment }")).is_some());
+        assert!(templates.apply_to(&pod("{ apiVersion: apps/v1beta1, kind: Deployment }")).is_none());
+        assert!(templates.apply_to(&pod("{ apiVersion: apps/v1, kind: DaemonSet }")).is_none());
+    }
+
+    #[test]
+    fn filters_by_request() {
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          operations: [ CREATE, UPDATE ]
+          userGroups: [ "system:serviceaccounts", "!system:masters" ]
+          dryRun: false
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let request = |json: serde_json::Value| serde_json::from_value::<AdmissionRequest>(json).unwrap();
+        let target = pod("{ apiVersion: v1, kind: Pod }");
+        let matching = request(serde_json::json!({
+            "uid": "1", "operation": "UPDATE", "userInfo": { "groups": [ "system:serviceaccounts" ] }
+        }));
+        assert!(templates.apply(&target, Some(&matching)).is_some());
+        let deleting = request(serde_json::json!({
+            "uid": "1", "operation": "DELETE", "userInfo": { "groups": [ "system:serviceaccounts" ] }
+        }));
+        assert!(templates.apply(&target, Some(&deleting)).is_none());
+        let admin = request(serde_json::json!({
+            "uid": "1", "operation": "CREATE", "userInfo": { "groups": [ "system:serviceaccounts", "system:masters" ] }
+        }));
+        assert!(templates.apply(&target, Some(&admin)).is_none());
+        let dry_run = request(serde_json::json!({
+            "uid": "1", "operation": "CREATE", "userInfo": { "groups": [ "system:serviceaccounts" ] }, "dryRun": true
+        }));
+        assert!(templates.apply(&target, Some(&dry_run)).is_none());
+        assert!(templates.apply_to(&target).is_none(), "request restrictions can't be met without a request");
+    }
+
+    #[test]
+    fn filters_by_when_clause() {
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          when:
+          - path: $.spec.containers[*].image
+            startsWith: registry.internal/
+          - path: $.spec.hostNetwork
+            exists: false
+        - apiVersion: v1
+          kind: Service
+          when:
+          - path: $.spec.type
+            equals: LoadBalancer
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        assert!(templates.apply_to(&pod("{ apiVersion: v1, kind: Pod, spec: { containers: [ { image: docker.io/a }, { image: registry.internal/b } ] } }")).is_some());
+        assert!(templates.apply_to(&pod("{ apiVersion: v1, kind: Pod, spec: { containers: [ { image: docker.io/a } ] } }")).is_none());
+        assert!(templates.apply_to(&pod("{ apiVersion: v1, kind: Pod, spec: { hostNetwork: true, containers: [ { image: registry.internal/b } ] } }")).is_none());
+        assert!(templates.apply_to(&pod("{ apiVersion: v1, kind: Service, spec: { type: LoadBalancer } }")).is_some());
+        assert!(templates.apply_to(&pod("{ apiVersion: v1, kind: Service, spec: { type: ClusterIP } }")).is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_when_clause() {
+        let yaml = r#"
+        templates:
+        - name: images
+          apiVersion: v1
+          kind: Pod
+          when:
+          - path: spec.containers
+        "#;
+        let err = Templates::construct_templates(yaml).err().unwrap();
+        assert!(err.contains("images") && err.contains("JSONPath"), "{}", err);
+    }
+
+    #[test]
+    fn filters_by_match_conditions() {
+        let yaml = r#"
+        namespaceLabels:
+          team-a:
+            env: prod
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          matchConditions:
+          - name: scaled-up
+            expression: object.spec.replicas > oldObject.spec.replicas
+          - name: production
+            expression: namespaceObject.metadata.labels.env == "prod"
+          - name: not-system
+            expression: '!request.userInfo.username.startsWith("system:")'
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let target = pod("{ apiVersion: v1, kind: Pod, metadata: { namespace: team-a }, spec: { replicas: 3 } }");
+        let request = |replicas: u32, username: &str| serde_json::from_value::<AdmissionRequest>(serde_json::json!({
+            "uid": "1",
+            "operation": "UPDATE",
+            "userInfo": { "username": username },
+            "oldObject": { "apiVersion": "v1", "kind": "Pod", "spec": { "replicas": replicas } },
+        })).unwrap();
+        assert!(templates.apply(&target, Some(&request(2, "alice"))).is_some());
+        assert!(templates.apply(&target, Some(&request(3, "alice"))).is_none());
+        assert!(templates.apply(&target, Some(&request(2, "system:admin"))).is_none());
+        let other_namespace = pod("{ apiVersion: v1, kind: Pod, metadata: { namespace: team-b }, spec: { replicas: 3 } }");
+        assert!(templates.apply(&other_namespace, Some(&request(2, "alice"))).is_none());
+        assert!(templates.apply_to(&target).is_none(), "conditions on the request fail without one");
+    }
+
+    #[test]
+    fn rejects_invalid_match_condition() {
+        let yaml = r#"
+        templates:
+        - name: broken
+          apiVersion: v1
+          kind: Pod
+          matchConditions:
+          - name: unfinished
+            expression: object.
+        "#;
+        let err = Templates::construct_templates(yaml).err().unwrap();
+        assert!(err.contains("broken") && err.contains("unfinished"), "{}", err);
+    }
+
+    #[test]
+    fn substitutes_variables_from_target() {
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          spec:
+            containers:
+              env:
+              - name: SERVICE_NAME
+                value: ${metadata.labels.app}
+              - name: OTEL_RESOURCE_ATTRIBUTES
+                value: service.name=${metadata.labels.app},k8s.pod.name=${metadata.name},k8s.namespace.name=${namespace}
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let target = pod(r#"
+        apiVersion: v1
+        kind: Pod
+        metadata:
+          name: checkout-1
+          namespace: shop
+          labels:
+            app: checkout
+        spec:
+          containers:
+          - name: app
+        "#);
+        let expected = pod(r#"
+        apiVersion: v1
+        kind: Pod
+        metadata:
+          name: checkout-1
+          namespace: shop
+          labels:
+            app: checkout
+        spec:
+          containers:
+          - name: app
+            env:
+            - name: SERVICE_NAME
+              value: checkout
+            - name: OTEL_RESOURCE_ATTRIBUTES
+              value: service.name=checkout,k8s.pod.name=checkout-1,k8s.namespace.name=shop
+        "#);
+        assert_eq!(Some(expected), templates.apply_to(&target));
+    }
+
+    #[test]
+    fn skips_templates_failing_substitution() {
+        let yaml = r#"
+        applyMode: all
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          metadata:
+            generateName: ${spec.containers}
+        - apiVersion: v1
+          kind: Pod
+          metadata:
+            finalizers:
+            - ${name}
+        - apiVersion: v1
+          kind: Pod
+          spec:
+            serviceAccountName: ${metadata.labels.team}
+        - apiVersion: v1
+          kind: Pod
+          spec:
+            containers:
+              args: [ "${HOME}" ]
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let target = pod(r#"
+        apiVersion: v1
+        kind: Pod
+        metadata:
+          name: checkout-1
+        spec:
+          containers:
+          - name: app
+        "#);
+        let applied = templates.apply(&target, None).unwrap();
+        assert_eq!(vec!["templates[1]".to_string(), "templates[3]".to_string()], applied.templates);
+        assert_eq!(2, applied.errors.len());
+        assert!(applied.errors[1].contains("${metadata.labels.team}"), "{:?}", applied.errors);
+        assert!(applied.resource.to_json().unwrap().contains(r#""args":["${HOME}"]"#));
+        let metadata = applied.resource.metadata.unwrap();
+        assert_eq!(None, metadata.generate_name);
+        assert_eq!(Some(vec!["checkout-1".to_string()]), metadata.finalizers);
+    }
+
+    #[test]
+    fn merges_rendered_body() {
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          spec:
+            dnsPolicy: None
+          body: |
+            metadata:
+              labels:
+                injected-by: {{ request.userInfo.username | default("unknown") }}
+            spec:
+              dnsPolicy: Default
+              containers:
+              {% for container in object.spec.containers %}
+              - name: {{ container.name }}
+                env:
+                - name: CONTAINER_NAME
+                  value: {{ container.name }}
+              {% endfor %}
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let target = pod("{ apiVersion: v1, kind: Pod, spec: { containers: [ { name: a }, { name: b } ] } }");
+        let expected = pod(r#"
+        apiVersion: v1
+        kind: Pod
+        metadata:
+          labels:
+            injected-by: unknown
+        spec:
+          dnsPolicy: None
+          containers:
+          - name: a
+            env:
+            - { name: CONTAINER_NAME, value: a }
+          - name: b
+            env:
+            - { name: CONTAINER_NAME, value: b }
+        "#);
+        assert_eq!(Some(expected), templates.apply_to(&target));
+    }
+
+    #[test]
+    fn reports_body_errors() {
+        let invalid = r#"
+        templates:
+        - name: broken
+          apiVersion: v1
+          kind: Pod
+          body: "spec: {% if %}"
+        "#;
+        let err = Templates::construct_templates(invalid).err().unwrap();
+        assert!(err.contains("broken") && err.contains("body"), "{}", err);
+
+        let failing = r#"
+        applyMode: all
+        templates:
+        - name: failing
+          apiVersion: v1
+          kind: Pod
+          body: "spec: {{ object.spec.replicas + 'x' }}"
+        - name: working
+          apiVersion: v1
+          kind: Pod
+          spec:
+            replicas: 2
+        "#;
+        let templates = Templates::construct_templates(failing).unwrap();
+        let applied = templates.apply(&pod("{ apiVersion: v1, kind: Pod, spec: { replicas: 1 } }"), None).unwrap();
+        assert_eq!(vec!["working"], applied.templates);
+        assert_eq!(1, applied.errors.len());
+        assert!(applied.errors[0].contains("failing"), "{:?}", applied.errors);
+    }
+
+    #[test]
+    fn resolves_value_sources() {
+        std::env::set_var("TEMPLATES_TEST_SIDECAR_IMAGE", "registry.internal/sidecar:1.4");
+        let yaml = r#"
+        templates:
+        - apiVersion: v1
+          kind: Pod
+          metadata:
+            labels:
+              version: 2
+          spec:
+            containers:
+            - name: sidecar
+              image:
+                $valueFrom:
+                  env: TEMPLATES_TEST_SIDECAR_IMAGE
+        "#;
+        let templates = Templates::construct_templates(yaml).unwrap();
+        let target = pod("{ apiVersion: v1, kind: Pod, metadata: { labels: { version: '2' } } }");
+        let expected = pod(r#"
+        apiVersion: v1
+        kind: Pod
+        metadata:
+          labels:
+            version: '2'
+        spec:
+          containers:
+          - name: sidecar
+            image: registry.internal/sidecar:1.4
+        "#);
+        assert_eq!(Some(expected), templates.apply_to(&target));
+
+        let missing = yaml.replace("TEMPLATES_TEST_SIDECAR_IMAGE", "TEMPLATES_TEST_UNSET");
+        let err = Templates::construct_templates(&missing).err().unwrap();
+        assert!(err.contains("TEMPLATES_TEST_UNSET"), "{}", err);
+    }
+
+    fn injection_templates() -> Templates {
+        Templates::construct_templates(r#"
+        applyMode: all
+        injection:
+          skipKey: inject.example.com/skip
+          templatesKey: inject.example.com/templates
+        namespaceLabels:
+          legacy:
+            inject.example.com/skip: "true"
+        templates:
+        - name: logging
+          apiVersion: v1
+          kind: Pod
+          spec:
+            containers:
+            - name: fluentd
+        - name: metrics
+          apiVersion: v1
+          kind: Pod
+          spec:
+            containers:
+            - name: exporter
+        - name: debug
+          optIn: true
+          apiVersion: v1
+          kind: Pod
+          spec:
+            containers:
+            - name: debugger
+        "#).unwrap()
+    }
+
+    #[test]
+    fn skips_opted_out_objects_and_namespaces() {
+        let templates = injection_templates();
+        let applied = |yaml: &str| templates.apply(&pod(yaml), None).map(|applied| applied.templates);
+        assert_eq!(Some(vec![String::from("logging"), String::from("metrics")]), applied("{ apiVersion: v1, kind: Pod }"));
+        assert_eq!(None, applied("{ apiVersion: v1, kind: Pod, metadata: { annotations: { inject.example.com/skip: 'true' } } }"));
+        assert_eq!(None, applied("{ apiVersion: v1, kind: Pod, metadata: { namespace: legacy } }"));
+    }
+
+    #[test]
+    fn applies_requested_templates() {
+        let templates = injection_templates();
+        let applied = |yaml: &str| templates.apply(&pod(yaml), None).map(|applied| applied.templates);
+        assert_eq!(Some(vec![String::from("metrics"), String::from("debug")]),
+            applied("{ apiVersion: v1, kind: Pod, metadata: { annotations: { inject.example.com/templates: 'debug,metrics' } } }"));
+        assert_eq!(None, applied("{ apiVersion: v1, kind: Pod, metadata: { labels: { inject.example.com/templates: unknown } } }"));
+    }
+
     fn create_test_templates() -> Templates {
         let yaml = r#"
         templates:
@@ -299,4 +1633,126 @@ mod tests {
       assert_eq!(templates.templates[1].resource, barb);
     }
 
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn watch_reloads_changed_file() {
+      let dir = tempfile::tempdir().unwrap();
+      let file = dir.path().join("templates.yaml");
+      let file_name = file.to_str().unwrap();
+      std::fs::write(&file, "templates: []").unwrap();
+      let templates = Templates::from_file(file_name).unwrap().watch(file_name, Duration::from_millis(10));
+      assert!(templates.load().is_empty());
+
+      std::fs::write(&file, r#"
+      templates:
+      - apiVersion: v1
+        kind: Pod
+      "#).unwrap();
+      assert!(eventually(Duration::from_secs(2), || templates.load().len() == 1).await);
+      let loaded = templates.load_full();
+
+      std::fs::write(&file, "templates: [ not valid").unwrap();
+      assert!(!eventually(Duration::from_millis(100), || !Arc::ptr_eq(&loaded, &templates.load_full())).await, "previous templates should be kept");
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_changed_value_files() {
+      let dir = tempfile::tempdir().unwrap();
+      let file = dir.path().join("templates.yaml");
+      let file_name = file.to_str().unwrap();
+      let image_file = dir.path().join("image");
+      std::fs::write(&image_file, "sidecar:1").unwrap();
+      std::fs::write(&file, format!(r#"
+      templates:
+      - apiVersion: v1
+        kind: Pod
+        spec:
+          image: {{ $valueFrom: {{ file: '{}' }} }}
+      "#, image_file.display())).unwrap();
+      let templates = Templates::from_file(file_name).unwrap().watch(file_name, Duration::from_millis(10));
+      let image = |templates: &Templates| templates.templates[0].resource.to_json().unwrap();
+      assert!(image(&templates.load()).contains("sidecar:1"));
+
+      std::fs::write(&image_file, "sidecar:2").unwrap();
+      assert!(eventually(Duration::from_secs(2), || image(&templates.load()).contains("sidecar:2")).await);
+    }
+
+    #[test]
+    fn combines_documents() {
+        let templates = Templates::construct_templates(r#"
+applyMode: all
+templates:
+- name: baseline
+  apiVersion: v1
+  kind: Pod
+  spec:
+    restartPolicy: Never
+---
+templates:
+- apiVersion: v1
+  kind: Pod
+  spec:
+    hostNetwork: false
+policies:
+- name: team
+  requiredLabels: [ team ]
+"#).unwrap();
+        let names: Vec<&str> = templates.templates.iter().map(|template| template.name.as_str()).collect();
+        assert_eq!(vec!["baseline", "document 2#0"], names);
+        assert_eq!(Some("document 2"), templates.templates[1].source.as_deref());
+        let applied = templates.apply(&pod("{ apiVersion: v1, kind: Pod }"), None).unwrap();
+        assert_eq!(vec!["baseline", "document 2#0"], applied.templates);
+        assert_eq!(1, templates.validate(&pod("{ apiVersion: v1, kind: Pod }")).len());
+
+        let err = Templates::construct_templates("applyMode: all\n---\napplyMode: first").err().unwrap();
+        assert_eq!("applyMode is set in both document 1 and document 2", err);
+
+        let err = Templates::construct_templates("mergeKeys: { args: [ id ] }\n---\nmergeKeys: { args: [ name ] }").err().unwrap();
+        assert_eq!("mergeKeys args is set in both document 1 and document 2", err);
+        let err = Templates::construct_templates("namespaceLabels: { shop: { env: prod } }\n---\nnamespaceLabels: { shop: { env: dev } }").err().unwrap();
+        assert_eq!("namespaceLabels shop is set in both document 1 and document 2", err);
+        assert!(Templates::construct_templates("mergeKeys: { args: [ id ] }\n---\nmergeKeys: { args: [ id ] }").is_ok());
+    }
+
+    #[test]
+    fn loads_directory_reporting_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("10-baseline.yaml"), r#"
+        templates:
+        - name: baseline
+          apiVersion: v1
+          kind: Pod
+          priority: 1
+        "#).unwrap();
+        std::fs::write(dir.path().join("20-team.yaml"), r#"
+        templates:
+        - name: team
+          apiVersion: v1
+          kind: Pod
+        "#).unwrap();
+        let templates = Templates::from_file(dir.path().to_str().unwrap()).unwrap();
+        let sources: Vec<String> = templates.templates.iter()
+            .map(|template| template.source.as_deref().unwrap().rsplit('/').next().unwrap().to_string())
+            .collect();
+        assert_eq!(vec!["10-baseline.yaml", "20-team.yaml"], sources);
+
+        std::fs::write(dir.path().join("30-team.yaml"), "templates: [ { name: team, apiVersion: v1, kind: Pod } ]").unwrap();
+        let err = Templates::from_file(dir.path().to_str().unwrap()).err().unwrap();
+        assert!(err.contains("20-team.yaml and") && err.contains("30-team.yaml"), "{}", err);
+        std::fs::write(dir.path().join("30-team.yaml"), "templates: [ { name: broken, apiVersion: v1, kind: Pod, when: [ { path: '$[' } ] } ]").unwrap();
+        let err = Templates::from_file(dir.path().to_str().unwrap()).err().unwrap();
+        assert!(err.starts_with("Template broken (") && err.contains("30-team.yaml)"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_added_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        std::fs::write(dir.path().join("a.yaml"), "templates: [ { apiVersion: v1, kind: Pod } ]").unwrap();
+        let templates = Templates::from_file(path).unwrap().watch(path, Duration::from_millis(10));
+        assert_eq!(1, templates.load().len());
+
+        std::fs::write(dir.path().join("b.yaml"), "templates: [ { apiVersion: v1, kind: Service } ]").unwrap();
+        assert!(eventually(Duration::from_secs(2), || templates.load().len() == 2).await);
+    }
+
+}
@@ -0,0 +1,125 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use rustls::ServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+use crate::watch;
+
+fn load_certs(cert_file: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(cert_file).map_err(|err| format!("Failed to open {}: {}", cert_file, err))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("Failed to read certificates from {}: {}", cert_file, err))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_file));
+    }
+    Ok(certs)
+}
+
+fn load_key(key_file: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(key_file).map_err(|err| format!("Failed to open {}: {}", key_file, err))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| format!("Failed to read private key from {}: {}", key_file, err))?
+        .ok_or_else(|| format!("No private key found in {}", key_file))
+}
+
+/// Builds the rustls configuration from PEM encoded certificate chain and private key files.
+/// Both HTTP/2 and HTTP/1.1 are offered via ALPN.
+pub fn load_server_config(cert_file: &str, key_file: &str) -> Result<ServerConfig, String> {
+    let certs = load_certs(cert_file)?;
+    let key = load_key(key_file)?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|err| format!("Invalid certificate or key in {}/{}: {}", cert_file, key_file, err))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Loads the configuration and replaces it whenever the certificate or key files change.
+/// Connections use the configuration current when they are accepted, so in-flight connections
+/// carry on with the one they started with. A failed reload keeps the previous configuration.
+pub fn reloading_config(cert_file: &str, key_file: &str, interval: Duration) -> Result<Arc<ArcSwap<ServerConfig>>, String> {
+    let config = Arc::new(ArcSwap::from_pointee(load_server_config(cert_file, key_file)?));
+    let swappable = config.clone();
+    let (cert_file, key_file) = (cert_file.to_string(), key_file.to_string());
+    watch::watch_files(vec![cert_file.clone(), key_file.clone()], interval, move || {
+        match load_server_config(&cert_file, &key_file) {
+            Ok(new_config) => {
+                swappable.store(Arc::new(new_config));
+                log::info!("Reloaded TLS certificate {} and key {}", cert_file, key_file);
+            },
+            Err(err) => log::error!("Failed to reload TLS configuration, keeping the previous one: {}", err),
+        }
+    });
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::watch::eventually;
+
+    use super::{load_server_config, reloading_config};
+
+    fn write_temp(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn loads_certificate_and_key() {
+        let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let cert = write_temp(&certified.cert.pem());
+        let key = write_temp(&certified.key_pair.serialize_pem());
+        let config = load_server_config(cert.path().to_str().unwrap(), key.path().to_str().unwrap()).unwrap();
+        assert_eq!(vec![b"h2".to_vec(), b"http/1.1".to_vec()], config.alpn_protocols);
+    }
+
+    #[tokio::test]
+    async fn reloads_when_certificate_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_file = dir.path().join("tls.crt").to_str().unwrap().to_string();
+        let key_file = dir.path().join("tls.key").to_str().unwrap().to_string();
+        let write_certificate = || {
+            let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+            std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+            std::fs::write(&key_file, certified.key_pair.serialize_pem()).unwrap();
+        };
+        write_certificate();
+        let config = reloading_config(&cert_file, &key_file, Duration::from_millis(10)).unwrap();
+        let original = config.load_full();
+
+        let replaced = || !Arc::ptr_eq(&original, &config.load_full());
+
+        std::fs::write(&key_file, "not a key").unwrap();
+        assert!(!eventually(Duration::from_millis(100), replaced).await, "invalid key should keep the original config");
+
+        write_certificate();
+        assert!(eventually(Duration::from_secs(2), replaced).await, "config should have been replaced");
+    }
+
+    #[test]
+    fn fails_for_missing_files() {
+        let err = load_server_config("does-not-exist.crt", "does-not-exist.key").unwrap_err();
+        assert!(err.contains("does-not-exist.crt"), "{}", err);
+    }
+
+    #[test]
+    fn fails_without_key() {
+        let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let cert = write_temp(&certified.cert.pem());
+        let path = cert.path().to_str().unwrap();
+        let err = load_server_config(path, path).unwrap_err();
+        assert!(err.starts_with("No private key found"), "{}", err);
+    }
+
+}
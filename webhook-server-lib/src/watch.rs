@@ -0,0 +1,138 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+/// Each file with a hash of its contents, `None` for files that can't currently be read.
+/// Contents are used rather than modification times as mounted Secrets and ConfigMaps
+/// are updated by swapping symlinks, which doesn't always change the time seen.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Fingerprint(Vec<(String, Option<u64>)>);
+
+impl Fingerprint {
+    fn of(files: Vec<String>) -> Fingerprint {
+        Fingerprint(files.into_iter()
+            .map(|file| {
+                let hash = std::fs::read(&file).ok().map(|contents| {
+                    let mut hasher = DefaultHasher::new();
+                    contents.hash(&mut hasher);
+                    hasher.finish()
+                });
+                (file, hash)
+            })
+            .collect())
+    }
+}
+
+/// Polls the files every `interval`, calling `on_change` whenever any of their contents change
+pub fn watch_files<F>(files: Vec<String>, interval: Duration, on_change: F) -> JoinHandle<()>
+where F: Fn() + Send + Sync + 'static {
+    watch_listed_files(move || files.clone(), interval, on_change)
+}
+
+/// Polls the files `list` gives every `interval`, calling `on_change` whenever any of their
+/// contents change or files are added or removed. Each poll, and `on_change`, runs on a blocking
+/// thread, as both read files and `on_change` may well do a lot more.
+pub fn watch_listed_files<L, F>(list: L, interval: Duration, on_change: F) -> JoinHandle<()>
+where L: Fn() -> Vec<String> + Send + Sync + 'static, F: Fn() + Send + Sync + 'static {
+    let mut last = Fingerprint::of(list());
+    let watcher = Arc::new((list, on_change));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let (watcher, previous) = (watcher.clone(), last.clone());
+            match tokio::task::spawn_blocking(move || poll(&watcher.0, &watcher.1, &previous)).await {
+                Ok(Some(current)) => last = current,
+                Ok(None) => {},
+                Err(err) => log::error!("Failed polling for changes: {}", err),
+            }
+        }
+    })
+}
+
+/// Calls `on_change` if the files have changed since `last`, returning what they are now if they have
+fn poll<L, F>(list: &L, on_change: &F, last: &Fingerprint) -> Option<Fingerprint>
+where L: Fn() -> Vec<String>, F: Fn() {
+    let current = Fingerprint::of(list());
+    if current == *last {
+        return None;
+    }
+    log::info!("Change detected in {:?}", current.0.iter().map(|(file, _)| file).collect::<Vec<_>>());
+    on_change();
+    Some(current)
+}
+
+/// Polls the condition until it holds or `within` has passed, returning whether it held
+#[cfg(test)]
+pub(crate) async fn eventually<F: Fn() -> bool>(within: Duration, condition: F) -> bool {
+    let deadline = tokio::time::Instant::now() + within;
+    while !condition() {
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::{eventually, watch_files, watch_listed_files};
+
+    const CHANGED: Duration = Duration::from_secs(2);
+    //Several polls, to be confident nothing was seen
+    const UNCHANGED: Duration = Duration::from_millis(100);
+
+    #[tokio::test]
+    async fn calls_back_when_contents_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("watched.txt");
+        std::fs::write(&file, "one").unwrap();
+        let changes = Arc::new(AtomicUsize::new(0));
+        let counter = changes.clone();
+        let handle = watch_files(vec![file.to_str().unwrap().to_string()], Duration::from_millis(10), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert!(!eventually(UNCHANGED, || changes.load(Ordering::SeqCst) > 0).await, "no change expected before writing");
+
+        std::fs::write(&file, "two").unwrap();
+        assert!(eventually(CHANGED, || changes.load(Ordering::SeqCst) == 1).await);
+
+        std::fs::write(&file, "two").unwrap();
+        assert!(!eventually(UNCHANGED, || changes.load(Ordering::SeqCst) > 1).await, "same contents shouldn't count as a change");
+
+        std::fs::remove_file(&file).unwrap();
+        assert!(eventually(CHANGED, || changes.load(Ordering::SeqCst) == 2).await);
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn calls_back_when_files_are_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let changes = Arc::new(AtomicUsize::new(0));
+        let counter = changes.clone();
+        let list = move || std::fs::read_dir(&path).unwrap()
+            .map(|entry| entry.unwrap().path().to_str().unwrap().to_string())
+            .collect();
+        let handle = watch_listed_files(list, Duration::from_millis(10), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert!(!eventually(UNCHANGED, || changes.load(Ordering::SeqCst) > 0).await);
+
+        std::fs::write(dir.path().join("new.yaml"), "").unwrap();
+        assert!(eventually(CHANGED, || changes.load(Ordering::SeqCst) == 1).await);
+        handle.abort();
+    }
+
+}
@@ -0,0 +1,132 @@
+mod fake_api_server;
+mod test_server;
+
+use base64::Engine;
+use fake_api_server::FakeApiServer;
+use test_server::TestServer;
+use tokio::time::{sleep, Duration, Instant};
+
+fn mutation_template(name: &str, spec: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "apiVersion": "webhook-server.io/v1alpha1",
+        "kind": "MutationTemplate",
+        "metadata": { "name": name, "resourceVersion": "1" },
+        "spec": spec,
+    })
+}
+
+async fn patch(server: &TestServer) -> Option<serde_json::Value> {
+    let review = serde_json::json!({
+        "apiVersion": "admission.k8s.io/v1",
+        "kind": "AdmissionReview",
+        "request": {
+            "uid": "7c4e1a92-0c55-4f3b-a8f5-2d6e9b1c3f40",
+            "namespace": "default",
+            "object": { "apiVersion": "v1", "kind": "Pod", "metadata": { "name": "web" } },
+        }
+    });
+    let url = format!("http://localhost:{}/mutate", server.port());
+    let resp = reqwest::Client::new().post(url).json(&review).send().await.expect("failed posting review");
+    let response: serde_json::Value = resp.json().await.expect("response was not json");
+    response["response"]["patch"].as_str().map(|patch| {
+        let patch = base64::engine::general_purpose::STANDARD.decode(patch).expect("patch was not base64");
+        serde_json::from_slice(&patch).expect("patch was not json")
+    })
+}
+
+/// Posts reviews until the patch is the one expected, for up to five seconds
+async fn assert_patch(server: &TestServer, expected: Option<serde_json::Value>, message: &str) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let actual = patch(server).await;
+        if actual == expected || Instant::now() >= deadline {
+            assert_eq!(expected, actual, "{}", message);
+            return;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_serves_templates_of_mutation_templates() {
+    let api = FakeApiServer::start(vec![
+        mutation_template("restart", serde_json::json!({ "apiVersion": "v1", "kind": "Pod", "spec": { "restartPolicy": "Never" } })),
+    ]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let templates_file = dir.path().join("templates.yaml");
+    std::fs::write(&templates_file, "templates: []").unwrap();
+    let server = TestServer::with_templates_file(templates_file.to_str().unwrap()).with_template_resources(&api.url());
+    server.init_server().await;
+    api.watched().await;
+
+    assert_patch(&server, Some(serde_json::json!([ { "op": "add", "path": "/spec", "value": { "restartPolicy": "Never" } } ])),
+        "the listed template should be applied").await;
+
+    api.send("MODIFIED", mutation_template("restart", serde_json::json!({ "apiVersion": "v1", "kind": "Pod", "spec": { "restartPolicy": "OnFailure" } })));
+    assert_patch(&server, Some(serde_json::json!([ { "op": "add", "path": "/spec", "value": { "restartPolicy": "OnFailure" } } ])),
+        "the modified template should be applied").await;
+
+    api.send("ADDED", mutation_template("labels", serde_json::json!({ "apiVersion": "v1", "kind": "Service", "spec": { "type": "ClusterIP" } })));
+    api.send("DELETED", mutation_template("restart", serde_json::json!({ "apiVersion": "v1", "kind": "Pod" })));
+    assert_patch(&server, None, "the deleted template should no longer be applied").await;
+
+    std::fs::write(&templates_file, "templates: [ { apiVersion: v1, kind: Pod, spec: { hostNetwork: false } } ]").unwrap();
+    assert_patch(&server, Some(serde_json::json!([ { "op": "add", "path": "/spec", "value": { "hostNetwork": false } } ])),
+        "the templates file should still be watched").await;
+}
+
+#[tokio::test]
+async fn test_waits_before_watching_again_when_watches_end_straight_away() {
+    let api = FakeApiServer::start(vec![]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let templates_file = dir.path().join("templates.yaml");
+    std::fs::write(&templates_file, "templates: []").unwrap();
+    let server = TestServer::with_templates_file(templates_file.to_str().unwrap()).with_template_resources(&api.url());
+    server.init_server().await;
+    api.watched().await;
+
+    api.end_watches();
+    let before = api.watches();
+    sleep(Duration::from_millis(500)).await;
+    //Watches are started again every 50ms reload interval at most
+    let watches = api.watches() - before;
+    assert!(watches <= 12, "watched {} times in 500ms", watches);
+}
+
+#[tokio::test]
+async fn test_keeps_both_changes_when_the_file_and_a_mutation_template_change_together() {
+    let api = FakeApiServer::start(vec![]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let templates_file = dir.path().join("templates.yaml");
+    std::fs::write(&templates_file, "templates: []").unwrap();
+    let server = TestServer::with_templates_file(templates_file.to_str().unwrap()).with_template_resources(&api.url());
+    server.init_server().await;
+    api.watched().await;
+
+    for (index, policy) in ["Never", "OnFailure", "Always"].into_iter().enumerate() {
+        std::fs::write(&templates_file, format!("applyMode: all\ntemplates: [ {{ apiVersion: v1, kind: Pod, spec: {{ priority: {} }} }} ]", index)).unwrap();
+        api.send("MODIFIED", mutation_template("restart", serde_json::json!({ "apiVersion": "v1", "kind": "Pod", "spec": { "restartPolicy": policy } })));
+        assert_patch(&server, Some(serde_json::json!([ { "op": "add", "path": "/spec", "value": { "priority": index, "restartPolicy": policy } } ])),
+            "both the file's and the resource's changes should be applied").await;
+    }
+}
+
+#[tokio::test]
+async fn test_applies_mutation_templates_again_once_their_names_are_free() {
+    let api = FakeApiServer::start(vec![
+        mutation_template("restart", serde_json::json!({ "name": "restart", "apiVersion": "v1", "kind": "Pod", "spec": { "restartPolicy": "Never" } })),
+    ]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let templates_file = dir.path().join("templates.yaml");
+    std::fs::write(&templates_file, "templates: [ { name: restart, apiVersion: v1, kind: Pod, spec: { hostNetwork: false } } ]").unwrap();
+    let server = TestServer::with_templates_file(templates_file.to_str().unwrap()).with_template_resources(&api.url());
+    server.init_server().await;
+    api.watched().await;
+
+    assert_patch(&server, Some(serde_json::json!([ { "op": "add", "path": "/spec", "value": { "hostNetwork": false } } ])),
+        "the file's template should win the name").await;
+
+    std::fs::write(&templates_file, "templates: []").unwrap();
+    assert_patch(&server, Some(serde_json::json!([ { "op": "add", "path": "/spec", "value": { "restartPolicy": "Never" } } ])),
+        "the resource's template should be applied once the file no longer uses its name").await;
+}
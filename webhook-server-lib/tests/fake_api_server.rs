@@ -0,0 +1,119 @@
+use std::sync::{Arc, Mutex};
+
+use http_body_util::{channel::{Channel, Sender}, combinators::BoxBody, BodyExt, Full};
+use hyper::{body::{Bytes, Frame, Incoming}, service::service_fn, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tokio::time::{sleep, Duration};
+
+const PATH: &str = "/apis/webhook-server.io/v1alpha1/mutationtemplates";
+
+/// Serves MutationTemplates as the Kubernetes API does: a list, then watches streaming events
+pub struct FakeApiServer {
+    port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    objects: Vec<serde_json::Value>,
+    resource_version: u64,
+    watchers: Vec<Sender<Bytes>>,
+    /// Watches started, including those ended straight away
+    watches: usize,
+    /// Whether watches are ended as soon as they're started, as some proxies do
+    end_watches: bool,
+}
+
+impl FakeApiServer {
+    pub async fn start(objects: Vec<serde_json::Value>) -> FakeApiServer {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(State { objects, resource_version: 1, ..Default::default() }));
+        let served = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let state = served.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| respond(req, state.clone()));
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        FakeApiServer { port, state }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// Waits for the webhook to start watching
+    pub async fn watched(&self) {
+        for _ in 0..100 {
+            if !self.state.lock().unwrap().watchers.is_empty() {
+                return;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Nothing started watching");
+    }
+
+    /// Ends the current watches and any started from now on straight away
+    pub fn end_watches(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.end_watches = true;
+        state.watchers.clear();
+    }
+
+    pub fn watches(&self) -> usize {
+        self.state.lock().unwrap().watches
+    }
+
+    /// Changes the objects as the event says and sends it to the watchers
+    pub fn send(&self, event_type: &str, mut object: serde_json::Value) {
+        let mut state = self.state.lock().unwrap();
+        state.resource_version += 1;
+        object["metadata"]["resourceVersion"] = serde_json::json!(state.resource_version.to_string());
+        let name = object["metadata"]["name"].clone();
+        state.objects.retain(|existing| existing["metadata"]["name"] != name);
+        if event_type != "DELETED" {
+            state.objects.push(object.clone());
+        }
+        let event = format!("{}\n", serde_json::json!({ "type": event_type, "object": object }));
+        state.watchers.retain_mut(|watcher| watcher.try_send(Frame::data(Bytes::from(event.clone()))).is_ok());
+    }
+}
+
+async fn respond(req: Request<Incoming>, state: Arc<Mutex<State>>) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    if req.uri().path() != PATH {
+        let mut response = Response::new(full(String::from("not found")));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    let mut state = state.lock().unwrap();
+    if req.uri().query().unwrap_or_default().contains("watch=true") {
+        state.watches += 1;
+        if state.end_watches {
+            return Ok(Response::new(full(String::new())));
+        }
+        let (sender, body) = Channel::<Bytes>::new(16);
+        state.watchers.push(sender);
+        return Ok(Response::new(body.map_err(|never| match never {}).boxed()));
+    }
+    let list = serde_json::json!({
+        "apiVersion": "webhook-server.io/v1alpha1",
+        "kind": "MutationTemplateList",
+        "metadata": { "resourceVersion": state.resource_version.to_string() },
+        "items": state.objects,
+    });
+    Ok(Response::new(full(list.to_string())))
+}
+
+fn full(body: String) -> BoxBody<Bytes, hyper::Error> {
+    Full::new(Bytes::from(body))
+        .map_err(|never| match never {})
+        .boxed()
+}
@@ -1,5 +1,8 @@
+use base64::Engine;
+
 mod test_server;
 use test_server::TestServer;
+use tokio::time::{sleep, Duration, Instant};
 
 #[tokio::test]
 async fn test_try_posting_returned_from_root() {
@@ -17,4 +20,239 @@ async fn test_try_posting_returned_from_root() {
         Ok(r) => assert_eq!(true, r.starts_with("Try POST")),
         Err(e) => panic!("Unexpected error: {}", e),
     }
-}
\ No newline at end of file
+}
+
+fn admission_review(object: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "apiVersion": "admission.k8s.io/v1",
+        "kind": "AdmissionReview",
+        "request": {
+            "uid": "0df28fbd-5f5f-4a81-9e9b-8d9b0b7c7f11",
+            "namespace": "not-default",
+            "operation": "CREATE",
+            "object": object,
+        }
+    })
+}
+
+async fn post_review(server: &TestServer, review: &serde_json::Value) -> serde_json::Value {
+    post_review_to(server, "/mutate", review).await
+}
+
+async fn post_review_to(server: &TestServer, path: &str, review: &serde_json::Value) -> serde_json::Value {
+    let url = format!("http://localhost:{}{}", server.port(), path);
+    let resp = reqwest::Client::new().post(url).json(review).send().await.expect("failed posting review");
+    assert_eq!(reqwest::StatusCode::OK, resp.status());
+    resp.json().await.expect("response was not json")
+}
+
+fn pobbly(annotation: &str) -> serde_json::Value {
+    serde_json::json!({
+        "apiVersion": "v2",
+        "kind": "Pob",
+        "metadata": {
+            "name": "pobbly",
+            "labels": { "sex": "male", "ages": "22" },
+            "annotations": { "io.kube.label1": annotation }
+        },
+        "spec": {
+            "containers": [ { "name": "pob", "image": "pob:latest" } ]
+        }
+    })
+}
+
+#[tokio::test]
+async fn test_mutate_returns_patch_for_matching_pob() {
+    let server = TestServer::new();
+    server.init_server().await;
+
+    let response = post_review(&server, &admission_review(pobbly("silly"))).await;
+    assert_eq!("admission.k8s.io/v1", response["apiVersion"]);
+    assert_eq!("AdmissionReview", response["kind"]);
+    assert_eq!("0df28fbd-5f5f-4a81-9e9b-8d9b0b7c7f11", response["response"]["uid"]);
+    assert_eq!(true, response["response"]["allowed"]);
+    assert_eq!("JSONPatch", response["response"]["patchType"]);
+
+    let patch = base64::engine::general_purpose::STANDARD
+        .decode(response["response"]["patch"].as_str().expect("patch was missing"))
+        .expect("patch was not base64");
+    let patch: serde_json::Value = serde_json::from_slice(&patch).expect("patch was not json");
+    let expected = serde_json::json!([
+        { "op": "add", "path": "/spec/containers/0/env", "value": [ { "name": "POBBLY", "value": "silly" } ] }
+    ]);
+    assert_eq!(expected, patch);
+}
+
+#[tokio::test]
+async fn test_mutate_allows_non_matching_pob_unchanged() {
+    let server = TestServer::new();
+    server.init_server().await;
+
+    let response = post_review(&server, &admission_review(pobbly("sensible"))).await;
+    assert_eq!(true, response["response"]["allowed"]);
+    assert!(response["response"].get("patch").is_none());
+    assert!(response["response"].get("patchType").is_none());
+}
+
+#[tokio::test]
+async fn test_mutate_rejects_invalid_review() {
+    let server = TestServer::new();
+    server.init_server().await;
+
+    let url = format!("http://localhost:{}/mutate", server.port());
+    let resp = reqwest::Client::new().post(url).body("not a review").send().await.expect("failed posting");
+    assert_eq!(reqwest::StatusCode::BAD_REQUEST, resp.status());
+}
+
+#[tokio::test]
+async fn test_unknown_path_not_found() {
+    let server = TestServer::new();
+    server.init_server().await;
+
+    let url = format!("http://localhost:{}/echo", server.port());
+    let resp = reqwest::Client::new().post(url).body("hello").send().await.expect("failed posting");
+    assert_eq!(reqwest::StatusCode::NOT_FOUND, resp.status());
+}
+
+#[tokio::test]
+async fn test_mutate_uses_reloaded_templates() {
+    let dir = tempfile::tempdir().unwrap();
+    let templates_file = dir.path().join("templates.yaml");
+    std::fs::write(&templates_file, "templates: []").unwrap();
+    let server = TestServer::with_templates_file(templates_file.to_str().unwrap());
+    server.init_server().await;
+
+    let response = post_review(&server, &admission_review(pobbly("silly"))).await;
+    assert!(response["response"].get("patch").is_none());
+
+    std::fs::write(&templates_file, r#"
+    templates:
+    - apiVersion: v2
+      kind: Pob
+      spec:
+        restartPolicy: Never
+    "#).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let response = post_review(&server, &admission_review(pobbly("silly"))).await;
+        if response["response"]["patchType"] == "JSONPatch" {
+            break;
+        }
+        assert!(Instant::now() < deadline, "changed templates should be applied");
+        sleep(Duration::from_millis(20)).await;
+    }
+
+    std::fs::write(&templates_file, "templates: [ broken").unwrap();
+    //Keep checking for a few reload intervals, as nothing changes when the broken file is read
+    let deadline = Instant::now() + Duration::from_millis(300);
+    while Instant::now() < deadline {
+        let response = post_review(&server, &admission_review(pobbly("silly"))).await;
+        assert_eq!("JSONPatch", response["response"]["patchType"], "previous templates should still be applied");
+        sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_validate_denies_policy_violations() {
+    let dir = tempfile::tempdir().unwrap();
+    let templates_file = dir.path().join("templates.yaml");
+    std::fs::write(&templates_file, r#"
+    policies:
+    - name: pob-registries
+      gvk: { kinds: [ Pob ] }
+      forbiddenRegistries: [ docker.io ]
+    "#).unwrap();
+    let server = TestServer::with_templates_file(templates_file.to_str().unwrap());
+    server.init_server().await;
+
+    let response = post_review_to(&server, "/validate", &admission_review(pobbly("silly"))).await;
+    assert_eq!("0df28fbd-5f5f-4a81-9e9b-8d9b0b7c7f11", response["response"]["uid"]);
+    assert_eq!(false, response["response"]["allowed"]);
+    assert_eq!(403, response["response"]["status"]["code"]);
+    assert_eq!("pob-registries: container pob uses image pob:latest from forbidden registry docker.io",
+        response["response"]["status"]["message"]);
+
+    let mut allowed = pobbly("silly");
+    allowed["spec"]["containers"][0]["image"] = serde_json::json!("registry.internal/pob:latest");
+    let response = post_review_to(&server, "/validate", &admission_review(allowed)).await;
+    assert_eq!(true, response["response"]["allowed"]);
+    assert!(response["response"].get("status").is_none());
+}
+
+fn fixture(name: &str) -> serde_json::Value {
+    let file = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    serde_json::from_str(&std::fs::read_to_string(&file).expect("missing fixture")).expect("fixture was not json")
+}
+
+async fn assert_round_trip(version: &str) {
+    let server = TestServer::with_templates_file(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/templates.yaml"));
+    server.init_server().await;
+
+    let response = post_review(&server, &fixture(&format!("{}-request.json", version))).await;
+    assert_eq!(fixture(&format!("{}-response.json", version)), response);
+}
+
+#[tokio::test]
+async fn test_v1_review_round_trip() {
+    assert_round_trip("v1").await;
+}
+
+#[tokio::test]
+async fn test_v1beta1_review_round_trip() {
+    assert_round_trip("v1beta1").await;
+}
+
+#[tokio::test]
+async fn test_routes_use_their_own_templates() {
+    let dir = tempfile::tempdir().unwrap();
+    let sidecars = dir.path().join("sidecars.yaml");
+    std::fs::write(&sidecars, r#"
+    templates:
+    - apiVersion: v2
+      kind: Pob
+      spec:
+        containers:
+        - name: sidecar
+          image: sidecar:1
+    "#).unwrap();
+    let defaults = dir.path().join("defaults.yaml");
+    std::fs::write(&defaults, r#"
+    templates:
+    - apiVersion: v2
+      kind: Pob
+      spec:
+        restartPolicy: Never
+    policies:
+    - name: team
+      requiredLabels: [ team ]
+    "#).unwrap();
+    let server = TestServer::new()
+        .with_route("/mutate/sidecars", sidecars.to_str().unwrap())
+        .with_route("/mutate/defaults", defaults.to_str().unwrap());
+    server.init_server().await;
+
+    let decode = |response: serde_json::Value| -> serde_json::Value {
+        let patch = base64::engine::general_purpose::STANDARD
+            .decode(response["response"]["patch"].as_str().expect("patch was missing"))
+            .expect("patch was not base64");
+        serde_json::from_slice(&patch).expect("patch was not json")
+    };
+    let review = admission_review(pobbly("sensible"));
+    let patch = decode(post_review_to(&server, "/mutate/sidecars", &review).await);
+    assert_eq!(serde_json::json!([
+        { "op": "add", "path": "/spec/containers/1", "value": { "name": "sidecar", "image": "sidecar:1" } }
+    ]), patch);
+    let patch = decode(post_review_to(&server, "/mutate/defaults", &review).await);
+    assert_eq!(serde_json::json!([ { "op": "add", "path": "/spec/restartPolicy", "value": "Never" } ]), patch);
+    let response = post_review(&server, &review).await;
+    assert!(response["response"].get("patch").is_none(), "/mutate still uses the templates file");
+
+    let response = post_review_to(&server, "/mutate/defaults/validate", &review).await;
+    assert_eq!(serde_json::json!(false), response["response"]["allowed"], "the route's policies should be checked");
+    let response = post_review_to(&server, "/mutate/sidecars/validate", &review).await;
+    assert_eq!(serde_json::json!(true), response["response"]["allowed"], "the route has no policies");
+
+    let url = format!("http://localhost:{}/mutate/security", server.port());
+    let resp = reqwest::Client::new().post(url).json(&review).send().await.expect("failed posting");
+    assert_eq!(reqwest::StatusCode::NOT_FOUND, resp.status());
+}
@@ -5,7 +5,7 @@ use std::net::TcpListener;
 use tokio::time::{sleep, Duration};
 
 extern crate webhook_server_lib;
-use webhook_server_lib::config::Args;
+use webhook_server_lib::config::{Args, Route};
 use webhook_server_lib::templates::Templates;
 
 pub struct TestServer {
@@ -14,17 +14,56 @@ pub struct TestServer {
     templates: Templates,
 }
 
+impl Default for TestServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TestServer {
     pub fn new() -> TestServer {
         let args = Self::args();
         let templates = Self::templates(&args);
         TestServer {
             started: AtomicBool::new(false),
-            args: args,
-            templates: templates,
+            args,
+            templates,
         }
     }
 
+    /// Serves HTTPS using the given PEM files
+    #[allow(dead_code)]
+    pub fn with_tls(cert_file: &str, key_file: &str) -> TestServer {
+        let mut server = Self::new();
+        server.args.tls_cert = Some(String::from(cert_file));
+        server.args.tls_key = Some(String::from(key_file));
+        server
+    }
+
+    /// Loads, and watches, the templates from the given file
+    #[allow(dead_code)]
+    pub fn with_templates_file(templates_file: &str) -> TestServer {
+        let mut server = Self::new();
+        server.args.templates_file = String::from(templates_file);
+        server.templates = Self::templates(&server.args);
+        server
+    }
+
+    /// Also serves the templates in the given file at `path`
+    #[allow(dead_code)]
+    pub fn with_route(mut self, path: &str, templates_file: &str) -> TestServer {
+        self.args.routes.push(Route { path: String::from(path), templates_file: String::from(templates_file) });
+        self
+    }
+
+    /// Also serves the templates of the MutationTemplates from the API server at `url`
+    #[allow(dead_code)]
+    pub fn with_template_resources(mut self, url: &str) -> TestServer {
+        self.args.template_resources = true;
+        self.args.kube_api_url = Some(String::from(url));
+        self
+    }
+
     pub fn port(&self) -> u16 {
         self.args.port
     }
@@ -41,8 +80,14 @@ impl TestServer {
         Args {
             log_file: log_config,
             address: String::from("0.0.0.0"),
-            port: port,
-            templates_file: templates_file,
+            port,
+            templates_file,
+            tls_cert: None,
+            tls_key: None,
+            reload_interval_ms: 50,
+            routes: vec![],
+            template_resources: false,
+            kube_api_url: None,
         }
     }
 
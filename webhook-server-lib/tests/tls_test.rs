@@ -0,0 +1,128 @@
+mod test_server;
+use test_server::TestServer;
+
+struct Certificates {
+    _dir: tempfile::TempDir,
+    ca: reqwest::Certificate,
+    cert_file: String,
+    key_file: String,
+}
+
+fn self_signed() -> Certificates {
+    let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("tls.crt");
+    let key_file = dir.path().join("tls.key");
+    std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+    std::fs::write(&key_file, certified.key_pair.serialize_pem()).unwrap();
+    Certificates {
+        ca: reqwest::Certificate::from_pem(certified.cert.pem().as_bytes()).unwrap(),
+        cert_file: cert_file.to_str().unwrap().to_string(),
+        key_file: key_file.to_str().unwrap().to_string(),
+        _dir: dir,
+    }
+}
+
+#[tokio::test]
+async fn test_serves_https_with_certificate() {
+    let certificates = self_signed();
+    let server = TestServer::with_tls(&certificates.cert_file, &certificates.key_file);
+    server.init_server().await;
+
+    let client = reqwest::Client::builder()
+        .add_root_certificate(certificates.ca.clone())
+        .build()
+        .unwrap();
+    let resp = client.get(format!("https://localhost:{}", server.port()))
+        .send().await
+        .expect("https request failed");
+    assert_eq!(reqwest::StatusCode::OK, resp.status());
+    assert!(resp.text().await.unwrap().starts_with("Try POST"));
+}
+
+#[tokio::test]
+async fn test_mutate_over_https() {
+    let certificates = self_signed();
+    let server = TestServer::with_tls(&certificates.cert_file, &certificates.key_file);
+    server.init_server().await;
+
+    let client = reqwest::Client::builder()
+        .add_root_certificate(certificates.ca.clone())
+        .build()
+        .unwrap();
+    let review = serde_json::json!({
+        "apiVersion": "admission.k8s.io/v1",
+        "kind": "AdmissionReview",
+        "request": {
+            "uid": "b2c8a3f4-0a43-4f43-8f4b-9a2b4e7e2f01",
+            "object": { "apiVersion": "v1", "kind": "ConfigMap", "metadata": { "name": "cm" } }
+        }
+    });
+    let resp: serde_json::Value = client.post(format!("https://localhost:{}/mutate", server.port()))
+        .json(&review)
+        .send().await
+        .expect("https request failed")
+        .json().await
+        .expect("response was not json");
+    assert_eq!("b2c8a3f4-0a43-4f43-8f4b-9a2b4e7e2f01", resp["response"]["uid"]);
+    assert_eq!(true, resp["response"]["allowed"]);
+}
+
+#[tokio::test]
+async fn test_rejects_untrusted_client() {
+    let certificates = self_signed();
+    let server = TestServer::with_tls(&certificates.cert_file, &certificates.key_file);
+    server.init_server().await;
+
+    let result = reqwest::get(format!("https://localhost:{}", server.port())).await;
+    assert!(result.is_err(), "self-signed certificate should not be trusted by default");
+}
+
+#[tokio::test]
+async fn test_plain_http_not_served_with_tls() {
+    let certificates = self_signed();
+    let server = TestServer::with_tls(&certificates.cert_file, &certificates.key_file);
+    server.init_server().await;
+
+    let result = reqwest::get(format!("http://localhost:{}", server.port())).await;
+    assert!(result.is_err(), "plain http should fail against the https listener");
+}
+
+#[tokio::test]
+async fn test_reloads_rotated_certificate() {
+    let original = self_signed();
+    let server = TestServer::with_tls(&original.cert_file, &original.key_file);
+    server.init_server().await;
+    let url = format!("https://localhost:{}", server.port());
+
+    let trusts_original = reqwest::Client::builder()
+        .add_root_certificate(original.ca.clone())
+        .build()
+        .unwrap();
+    assert!(trusts_original.get(&url).send().await.is_ok());
+
+    //Rotate in place as cert-manager would
+    let rotated = self_signed();
+    std::fs::copy(&rotated.cert_file, &original.cert_file).unwrap();
+    std::fs::copy(&rotated.key_file, &original.key_file).unwrap();
+
+    let trusts_rotated = reqwest::Client::builder()
+        .add_root_certificate(rotated.ca.clone())
+        .build()
+        .unwrap();
+    let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(5);
+    let resp = loop {
+        match trusts_rotated.get(&url).send().await {
+            Ok(resp) => break resp,
+            Err(err) if tokio::time::Instant::now() >= deadline => panic!("rotated certificate should be served: {}", err),
+            Err(_) => tokio::time::sleep(tokio::time::Duration::from_millis(20)).await,
+        }
+    };
+    assert_eq!(reqwest::StatusCode::OK, resp.status());
+
+    let fresh_original = reqwest::Client::builder()
+        .add_root_certificate(original.ca.clone())
+        .build()
+        .unwrap();
+    assert!(fresh_original.get(&url).send().await.is_err(), "original certificate should no longer be served");
+}